@@ -0,0 +1,442 @@
+use crate::error::{ErrorKind, QError};
+use crate::id::JobId;
+use crate::queue::{JobFilter, Queue, RetryOptions};
+use crate::{err, QResult};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// the largest request body read
+const MAX_BODY: usize = 64 * 1024;
+
+/// how long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the retry, cancel and promote actions over HTTP, so incident tooling can act on
+/// jobs without the CLI. Every request is a `POST` with `Authorization: Bearer <token>`
+/// and is answered with json:
+/// * `/channels/{channel}/jobs/{id}/retry` kicks a buried job, `.../cancel` removes a job
+///   and `.../promote` makes a delayed job waiting, answered with `{"applied": true}`,
+///   false when the job was not in that state
+/// * `/channels/{channel}/retry`, `/cancel` and `/promote` act on the jobs matching the
+///   `JobFilter` of the json body, such as
+///   `{"job_type": "SendEmail", "tag": "billing", "buried_since": 1700000000}`, a retry
+///   also reads the fields of `RetryOptions`. Answered with `{"ids": [...]}`, a cancel
+///   needs at least one condition so an empty body never removes a whole channel
+///
+/// Channels other than the one of the queue use its settings. Connections are served one
+/// at a time
+#[derive(Debug)]
+pub struct AdminServer {
+    queue: Queue,
+    token: String,
+}
+
+/// What a request asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Retry,
+    Cancel,
+    Promote,
+}
+
+/// The jobs a request acts on
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+    /// one job of a channel
+    Job {
+        channel: String,
+        id: JobId,
+        action: Action,
+    },
+    /// the jobs of a channel matching the filter of the body
+    Filter { channel: String, action: Action },
+}
+
+/// The body of a filtered action
+#[derive(Debug, Default, Deserialize)]
+struct FilterBody {
+    #[serde(flatten)]
+    filter: JobFilter,
+    #[serde(flatten)]
+    options: RetryOptions,
+}
+
+/// A request read from a connection
+#[derive(Debug, Default)]
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+/// an io error as a queue error
+fn io_error(e: std::io::Error) -> QError {
+    QError::new(ErrorKind::Other, e.to_string())
+}
+
+/// the route of a request [path], `None` for a path this server does not serve
+fn route(path: &str) -> Option<Route> {
+    let path = path.split('?').next().unwrap_or_default();
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let action = |name: &str| match name {
+        "retry" => Some(Action::Retry),
+        "cancel" => Some(Action::Cancel),
+        "promote" => Some(Action::Promote),
+        _ => None,
+    };
+    match parts.as_slice() {
+        ["channels", channel, name] if !channel.is_empty() => Some(Route::Filter {
+            channel: channel.to_string(),
+            action: action(name)?,
+        }),
+        ["channels", channel, "jobs", id, name] if !channel.is_empty() => Some(Route::Job {
+            channel: channel.to_string(),
+            id: id.parse().ok()?,
+            action: action(name)?,
+        }),
+        _ => None,
+    }
+}
+
+/// read the request line, the headers and the body of a request
+fn read_request(stream: impl Read) -> QResult<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(io_error)?;
+    let mut words = line.split_whitespace();
+    let (Some(method), Some(path)) = (words.next(), words.next()) else {
+        return err!(
+            ErrorKind::InvalidPayload,
+            "invalid request line [{}]",
+            line.trim()
+        );
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(io_error)?;
+        if header.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            length = match value.trim().parse() {
+                Ok(length) if length <= MAX_BODY => length,
+                _ => return err!(ErrorKind::InvalidPayload, "invalid content length"),
+            };
+        } else if name.eq_ignore_ascii_case("authorization") {
+            request.authorization = Some(value.trim().to_string());
+        }
+    }
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body).map_err(io_error)?;
+    Ok(request)
+}
+
+/// compare without returning early, so the time taken does not tell how much of a
+/// guessed token is right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl AdminServer {
+    /// serve the channels of [queue] to requests carrying [token]
+    pub fn new(queue: Queue, token: impl Into<String>) -> Self {
+        AdminServer {
+            queue,
+            token: token.into(),
+        }
+    }
+    /// answer the connections of [listener] until it fails
+    pub fn serve(&self, listener: TcpListener) -> QResult<()> {
+        if let Ok(addr) = listener.local_addr() {
+            info!("Admin server listening on [{}]", addr);
+        }
+        for stream in listener.incoming() {
+            let stream = stream.map_err(io_error)?;
+            if let Err(e) = self.answer(stream) {
+                warn!("Admin request not answered: {}", e);
+            }
+        }
+        Ok(())
+    }
+    /// read a request from [stream] and write its response
+    fn answer(&self, mut stream: TcpStream) -> QResult<()> {
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .map_err(io_error)?;
+        let (status, body) = match read_request(&stream) {
+            Ok(request) => self.handle(&request),
+            Err(e) => (400, json!({ "error": e.to_string() })),
+        };
+        let body = body.to_string();
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            reason,
+            body.len(),
+            body
+        )
+        .map_err(io_error)
+    }
+    /// the status and the json body answering [request]
+    fn handle(&self, request: &Request) -> (u16, Value) {
+        let expected = format!("Bearer {}", self.token);
+        let authorized = request
+            .authorization
+            .as_deref()
+            .is_some_and(|given| same(given.as_bytes(), expected.as_bytes()));
+        if !authorized {
+            return (401, json!({ "error": "missing or wrong bearer token" }));
+        }
+        if request.method != "POST" {
+            return (405, json!({ "error": "only POST is served" }));
+        }
+        let Some(route) = route(&request.path) else {
+            return (404, json!({ "error": "no such route" }));
+        };
+        match self.apply(route, &request.body) {
+            Ok(body) => (200, body),
+            Err(e) if matches!(e.kind(), ErrorKind::JsonConvert | ErrorKind::InvalidPayload) => {
+                (400, json!({ "error": e.to_string() }))
+            }
+            Err(e) => {
+                warn!("Admin action failed: {}", e);
+                (500, json!({ "error": e.to_string() }))
+            }
+        }
+    }
+    /// run the action of [route] and describe what it applied to
+    fn apply(&self, route: Route, body: &[u8]) -> QResult<Value> {
+        match route {
+            Route::Job {
+                channel,
+                id,
+                action,
+            } => {
+                let queue = self.channel(&channel);
+                let applied = match action {
+                    Action::Retry => queue.kick(&id)?,
+                    Action::Cancel => queue.remove(&id)?,
+                    Action::Promote => queue.promote(&id)?,
+                };
+                info!(
+                    "Admin {:?} of job id:[{}] applied:[{}]",
+                    action, id, applied
+                );
+                Ok(json!({ "applied": applied }))
+            }
+            Route::Filter { channel, action } => {
+                let FilterBody { filter, options } = match body {
+                    [] => FilterBody::default(),
+                    body => serde_json::from_slice(body)?,
+                };
+                if action == Action::Cancel && filter == JobFilter::default() {
+                    return err!(
+                        ErrorKind::InvalidPayload,
+                        "a cancel needs a job_type, tag or buried_since"
+                    );
+                }
+                let queue = self.channel(&channel);
+                let ids = match action {
+                    Action::Retry => queue.retry_where_with(&filter, &options)?,
+                    Action::Cancel => queue.cancel_where(&filter)?,
+                    Action::Promote => queue.promote_where(&filter)?,
+                };
+                info!(
+                    "Admin {:?} of [{}] jobs in channel [{}]",
+                    action,
+                    ids.len(),
+                    channel
+                );
+                let ids: Vec<&str> = ids.iter().map(JobId::as_str).collect();
+                Ok(json!({ "ids": ids }))
+            }
+        }
+    }
+    /// the queue of [channel] with the settings of the served queue
+    fn channel(&self, channel: &str) -> Queue {
+        let mut queue = self.queue.clone();
+        queue.channel(channel);
+        queue
+    }
+}
+
+// test admin
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{STATUS_DONE, STATUS_WAITING};
+    use crate::MakeJob;
+    use serde::Serialize;
+
+    #[derive(Serialize, Deserialize)]
+    struct AdminJob {}
+    #[MakeJob]
+    impl crate::job::JobTrait for AdminJob {
+        fn execute(&self) -> QResult<()> {
+            Ok(())
+        }
+    }
+
+    fn server() -> AdminServer {
+        let queue = Queue::new(
+            "test-admin",
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+        );
+        AdminServer::new(queue, "secret")
+    }
+
+    fn post(path: &str, body: &str) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: path.to_string(),
+            authorization: Some("Bearer secret".to_string()),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    // test request paths are routed to actions on jobs or filters
+    #[test]
+    fn test_route() {
+        assert_eq!(
+            route("/channels/emails/jobs/42/retry"),
+            Some(Route::Job {
+                channel: "emails".to_string(),
+                id: JobId::from(42),
+                action: Action::Retry,
+            })
+        );
+        assert_eq!(
+            route("/channels/emails.dlq/cancel?dry=1"),
+            Some(Route::Filter {
+                channel: "emails.dlq".to_string(),
+                action: Action::Cancel,
+            })
+        );
+        assert_eq!(route("/channels/emails/delete"), None);
+        assert_eq!(route("/channels//retry"), None);
+        assert_eq!(route("/channels/emails/jobs/42"), None);
+        assert_eq!(route("/stats"), None);
+    }
+    // test requests are read with their token and body
+    #[test]
+    fn test_read_request() {
+        let raw = "POST /channels/emails/retry HTTP/1.1\r\nAuthorization: Bearer secret\r\n\
+            content-length: 20\r\n\r\n{\"tag\":\"billing\"}...";
+        let request = read_request(raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/channels/emails/retry");
+        assert_eq!(request.authorization.as_deref(), Some("Bearer secret"));
+        assert_eq!(request.body, b"{\"tag\":\"billing\"}...");
+        let body: FilterBody =
+            serde_json::from_slice(b"{\"tag\":\"billing\",\"delay\":30}").unwrap();
+        assert_eq!(body.filter, JobFilter::tag("billing"));
+        assert_eq!(body.options.delay, 30);
+        let oversized = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert!(read_request(oversized.as_bytes()).is_err());
+        assert!(read_request("\r\n".as_bytes()).is_err());
+    }
+    // test requests without the token or with another method are refused before any action
+    #[test]
+    fn test_refused() {
+        let server = server();
+        let unauthorized = Request {
+            authorization: Some("Bearer guess".to_string()),
+            ..post("/channels/emails/cancel", "")
+        };
+        assert_eq!(server.handle(&unauthorized).0, 401);
+        let get = Request {
+            method: "GET".to_string(),
+            ..post("/channels/emails/cancel", "")
+        };
+        assert_eq!(server.handle(&get).0, 405);
+        assert_eq!(server.handle(&post("/stats", "")).0, 404);
+        assert!(same(b"secret", b"secret"));
+        assert!(!same(b"secret", b"secrets"));
+        // the same over a connection
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || server.serve(listener));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /channels/emails/cancel HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("{\"error\":\"missing or wrong bearer token\"}"));
+    }
+    // test jobs are retried, promoted and cancelled by id and by filter
+    #[test]
+    fn test_actions() {
+        let server = server();
+        let mut queue = server.channel("test-admin");
+        queue.clear().unwrap();
+        let id = queue.push(AdminJob {}).unwrap();
+        assert!(queue.bury(&id).unwrap());
+        let (status, body) = server.handle(&post(
+            &format!("/channels/test-admin/jobs/{}/retry", id),
+            "",
+        ));
+        assert_eq!((status, body), (200, json!({ "applied": true })));
+        assert_eq!(queue.status(&id).unwrap(), STATUS_WAITING);
+        let (_, body) = server.handle(&post(
+            &format!("/channels/test-admin/jobs/{}/promote", id),
+            "",
+        ));
+        assert_eq!(body, json!({ "applied": false }));
+        // a cancel without a condition is refused
+        assert_eq!(
+            server.handle(&post("/channels/test-admin/cancel", "")).0,
+            400
+        );
+        assert_eq!(
+            server.handle(&post("/channels/test-admin/cancel", "{")).0,
+            400
+        );
+        queue.tags(&["billing"]).delay(60);
+        let tagged = queue.push(AdminJob {}).unwrap();
+        let (_, body) = server.handle(&post(
+            "/channels/test-admin/promote",
+            "{\"tag\":\"billing\"}",
+        ));
+        assert_eq!(body, json!({ "ids": [tagged.as_str()] }));
+        let (_, body) = server.handle(&post(
+            "/channels/test-admin/cancel",
+            "{\"job_type\":\"AdminJob\"}",
+        ));
+        assert_eq!(body["ids"].as_array().unwrap().len(), 2);
+        assert_eq!(queue.status(&id).unwrap(), STATUS_DONE);
+        assert_eq!(queue.status(&tagged).unwrap(), STATUS_DONE);
+        queue.clear().unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+use crate::job::JobContext;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+
+/// the metadata prefix of ambient values in the envelope
+pub(crate) const PREFIX: &str = "env.";
+
+thread_local! {
+    static CURRENT: RefCell<BTreeMap<String, String>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// The ambient values a job was pushed with, such as the locale or the region of the
+/// request, read them with `get` while the job executes or from its `JobContext`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ambient(pub BTreeMap<String, String>);
+
+impl Ambient {
+    /// the ambient values stored in the metadata of an envelope
+    pub(crate) fn from_metadata(metadata: &BTreeMap<String, String>) -> Self {
+        let values = metadata
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(PREFIX)?.to_string(), value.clone())))
+            .collect();
+        Ambient(values)
+    }
+}
+
+/// set an ambient value of the current thread, every job pushed from this thread carries
+/// it until it is removed, as well as the jobs those jobs push
+pub fn set(key: impl Into<String>, value: impl Into<String>) {
+    CURRENT.with(|current| current.borrow_mut().insert(key.into(), value.into()));
+}
+
+/// get an ambient value of the current thread, or of the executing job on a worker
+pub fn get(key: &str) -> Option<String> {
+    CURRENT.with(|current| current.borrow().get(key).cloned())
+}
+
+/// remove an ambient value of the current thread
+pub fn remove(key: &str) {
+    CURRENT.with(|current| current.borrow_mut().remove(key));
+}
+
+/// the ambient values of the current thread
+pub fn current() -> BTreeMap<String, String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// run [f] with [values] as the ambient values of the current thread, the previous values
+/// are restored afterwards, also when [f] panics
+pub fn scope<R>(values: BTreeMap<String, String>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<BTreeMap<String, String>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if let Some(previous) = self.0.take() {
+                CURRENT.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+    }
+    let previous = CURRENT.with(|current| current.replace(values));
+    let _restore = Restore(Some(previous));
+    f()
+}
+
+/// run [f] with the ambient values of the job executing with [ctx]
+pub(crate) fn within<R>(ctx: &JobContext, f: impl FnOnce() -> R) -> R {
+    match ctx.get::<Ambient>() {
+        Some(Ambient(values)) => scope(values.clone(), f),
+        None => f(),
+    }
+}
+
+/// poll [future] with the ambient values of the job executing with [ctx], set again on
+/// every poll since the future may move between the threads of the runtime
+#[cfg(feature = "async")]
+pub(crate) fn within_async<'a, F: Future + Unpin + 'a>(
+    ctx: &'a JobContext,
+    mut future: F,
+) -> impl Future<Output = F::Output> + Unpin + 'a {
+    std::future::poll_fn(move |cx| within(ctx, || Pin::new(&mut future).poll(cx)))
+}
+
+// test ambient
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test scope restores the previous values
+    #[test]
+    fn test_scope() {
+        set("locale", "en");
+        let values = BTreeMap::from([("locale".to_string(), "fr".to_string())]);
+        let locale = scope(values, || get("locale"));
+        assert_eq!(locale.as_deref(), Some("fr"));
+        assert_eq!(get("locale").as_deref(), Some("en"));
+        let metadata = BTreeMap::from([
+            ("env.region".to_string(), "eu".to_string()),
+            ("src".to_string(), "web-1".to_string()),
+        ]);
+        let ambient = Ambient::from_metadata(&metadata);
+        assert_eq!(
+            ambient.0,
+            BTreeMap::from([("region".to_string(), "eu".to_string())])
+        );
+        remove("locale");
+        assert!(current().is_empty());
+    }
+}
@@ -0,0 +1,1024 @@
+use crate::ambient;
+use crate::backend::QueueBackend;
+use crate::config::ChannelConfig;
+use crate::envelope;
+use crate::error::{Context, ErrorKind};
+use crate::id::JobId;
+use crate::job::{AppState, AsyncJobTrait, JobContext, JobTrait};
+use crate::queue::{
+    self, Dispatcher, ExecutionOutcome, ReservedJob, DELETE_SCRIPT, NEXT_ID_SCRIPT, RESERVE_SCRIPT,
+};
+use crate::task::{undecodable, DecodeFailure, ExitCause, ProcessTitle, WorkerReport};
+use crate::{err, QError, QResult};
+use redis::aio::MultiplexedConnection;
+use serde::Serialize;
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::runtime::RuntimeMetrics;
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+/// how often a reserve with a timeout polls the waiting list, a blocking pop would stall
+/// every other command sharing the multiplexed connection
+const RESERVE_POLL: Duration = Duration::from_millis(100);
+
+/// A queue for async code, pushing, reserving and deleting on one multiplexed redis
+/// connection shared by every clone. It wraps a `queue::Queue` carrying the settings,
+/// the few features that only have a blocking implementation, such as the blob store,
+/// the push buffer, id generators and the maintenance pass, run on tokio's blocking pool
+#[derive(Clone)]
+pub struct Queue {
+    inner: Arc<queue::Queue>,
+    conn: MultiplexedConnection,
+}
+
+impl std::fmt::Debug for Queue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Queue").field("inner", &self.inner).finish()
+    }
+}
+
+/// run [f] on the blocking pool with the wrapped queue
+async fn blocking<T, F>(queue: &Arc<queue::Queue>, f: F) -> QResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&queue::Queue) -> QResult<T> + Send + 'static,
+{
+    let queue = Arc::clone(queue);
+    match tokio::task::spawn_blocking(move || f(&queue)).await {
+        Ok(result) => result,
+        Err(e) => err!("blocking queue call failed: {}", e),
+    }
+}
+
+/// await [future] like a spawned task, a panic while polling it is returned as its payload
+async fn catch_unwind<F: Future + Unpin>(mut future: F) -> Result<F::Output, Box<dyn Any + Send>> {
+    std::future::poll_fn(|cx| {
+        match panic::catch_unwind(AssertUnwindSafe(|| Pin::new(&mut future).poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    })
+    .await
+}
+
+impl Queue {
+    /// open the multiplexed connection of [queue], must be called within a tokio runtime
+    pub async fn new(queue: queue::Queue) -> QResult<Self> {
+        let inner = Arc::new(queue);
+        let client = blocking(&inner, |queue| queue.client()).await?;
+        let conn = client.get_multiplexed_async_connection().await?;
+        Ok(Queue { inner, conn })
+    }
+    /// the wrapped blocking queue, for the calls without an async version
+    pub fn blocking(&self) -> &queue::Queue {
+        &self.inner
+    }
+    /// Push a job to the queue
+    pub async fn push<T: JobTrait + Serialize>(&self, job: T) -> QResult<JobId> {
+        let job = &job as &dyn JobTrait;
+        job.validate().context("job rejected by validate")?;
+        let message = serde_json::to_string(job)?;
+        self.push_message(message)
+            .await
+            .with_context(|| format!("while pushing to channel [{}]", self.inner.name()))
+    }
+    /// Push a job whose execution awaits, workers run it next to the `JobTrait` jobs
+    pub async fn push_async_job<T: AsyncJobTrait + Serialize>(&self, job: T) -> QResult<JobId> {
+        let job = &job as &dyn AsyncJobTrait;
+        job.validate().context("job rejected by validate")?;
+        let message = serde_json::to_string(job)?;
+        self.push_message(message)
+            .await
+            .with_context(|| format!("while pushing to channel [{}]", self.inner.name()))
+    }
+    async fn push_message(&self, message: String) -> QResult<JobId> {
+        if self.inner.blocking_push() {
+            return blocking(&self.inner, move |queue| {
+                QueueBackend::push_message(queue, message)
+            })
+            .await;
+        }
+        self.guard_eviction().await?;
+        let mut conn = self.conn.clone();
+        let script = redis::Script::new(NEXT_ID_SCRIPT);
+        let id = match self.inner.counter_invocation(&script) {
+            Some(invocation) => self
+                .inner
+                .counted_id(invocation.invoke_async(&mut conn).await?)?,
+            None => JobId::ulid(),
+        };
+        let replication = self.inner.replication_pipeline(&message);
+        self.inner
+            .push_pipeline(&id, message)?
+            .query_async::<()>(&mut conn)
+            .await?;
+        if let Some(pipe) = replication {
+            if let Err(e) = pipe.query_async::<()>(&mut conn).await {
+                self.inner.replication_failed(&id, e);
+            }
+        }
+        self.touch().await?;
+        Ok(id)
+    }
+    /// reserve a job, polling the waiting list for up to [timeout] seconds, 0 checks once
+    pub async fn reserve(&self, timeout: u64) -> QResult<ReservedJob> {
+        self.reserve_message(timeout)
+            .await
+            .with_context(|| format!("while reserving from channel [{}]", self.inner.name()))
+    }
+    async fn reserve_message(&self, timeout: u64) -> QResult<ReservedJob> {
+        self.guard_eviction().await?;
+        if self.inner.inline_maintenance_due()? {
+            blocking(&self.inner, |queue| {
+                queue.maintain(&mut queue.connection()?)
+            })
+            .await?;
+        }
+        let mut conn = self.conn.clone();
+        let script = redis::Script::new(RESERVE_SCRIPT);
+        let token = ulid::Ulid::new().to_string();
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        let (id, attempts, payload) = loop {
+            let reserved: Option<(JobId, Option<u32>, Option<String>)> = self
+                .inner
+                .reserve_invocation(&script, None, &token)?
+                .invoke_async(&mut conn)
+                .await?;
+            match reserved {
+                Some(reserved) => break reserved,
+                None if Instant::now() + RESERVE_POLL <= deadline => {
+                    tokio::time::sleep(RESERVE_POLL).await;
+                }
+                None => {
+                    debug!("No job fetched from waiting list");
+                    return err!(ErrorKind::NotFound, "No job found");
+                }
+            }
+        };
+        let (Some(attempts), Some(payload)) = (attempts, payload) else {
+            return err!(
+                ErrorKind::InvalidPayload,
+                "missing message of job id:[{}]",
+                id
+            );
+        };
+        let envelope = if queue::Queue::stored_apart(&payload) {
+            let id = id.clone();
+            blocking(&self.inner, move |queue| {
+                queue.open_payload(Some(&mut queue.connection()?), &id, payload)
+            })
+            .await
+        } else {
+            self.inner.open_payload(None, &id, payload)
+        };
+        let envelope = match envelope {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                // keep the corrupted payload for inspection instead of retrying it forever
+                error!(
+                    "Parsed message from payload failed, id:[{}] {}, burying it",
+                    id, e
+                );
+                let buried = id.clone();
+                blocking(&self.inner, move |queue| queue.bury(&buried)).await?;
+                return Err(e);
+            }
+        };
+        self.touch().await?;
+        let attempted = attempts.to_string();
+        let summary = self.inner.summary_pipeline(
+            [&id],
+            &[("state", "reserved"), ("attempts", attempted.as_str())],
+        );
+        if let Some(pipe) = summary {
+            pipe.query_async::<()>(&mut conn).await?;
+        }
+        debug!(
+            "Fetched message successed id:[{}],ttr:[{}],attampts:[{}]",
+            id, envelope.ttr, attempts
+        );
+        Ok(ReservedJob {
+            id,
+            message: envelope.message,
+            ttr: envelope.ttr,
+            attempts,
+            token,
+            metadata: envelope.metadata,
+        })
+    }
+    /// delete a reserved job, the token must be the one returned by `reserve`.
+    /// return false if the reservation has expired and the job was reserved again by another worker
+    pub async fn delete(&self, message_id: &JobId, token: &str) -> QResult<bool> {
+        if self.inner.has_blob_store() {
+            let (message_id, token) = (message_id.clone(), token.to_string());
+            return blocking(&self.inner, move |queue| queue.delete(&message_id, &token)).await;
+        }
+        let script = redis::Script::new(DELETE_SCRIPT);
+        let deleted: bool = self
+            .inner
+            .delete_invocation(&script, message_id, token)
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        if deleted {
+            if let Some(pipe) = self.inner.forget_pipeline([message_id]) {
+                pipe.query_async::<()>(&mut self.conn.clone()).await?;
+            }
+            debug!("Deleted message successed id:[{}]", message_id);
+        } else {
+            warn!(
+                "Deleted message skipped id:[{}], the reservation is owned by another worker",
+                message_id
+            );
+        }
+        Ok(deleted)
+    }
+    /// settle a job after its execution, see `queue::Queue::settle`
+    pub async fn settle(&self, job: ReservedJob, outcome: ExecutionOutcome) -> QResult<bool> {
+        if !outcome.is_failure() {
+            return self.delete(&job.id, &job.token).await;
+        }
+        blocking(&self.inner, move |queue| queue.settle(&job, &outcome)).await
+    }
+    /// execute a reserved job and return how its execution ended, `AsyncJobTrait` jobs are
+    /// awaited on the runtime and the others executed on the blocking pool
+    pub async fn handle_message(
+        &self,
+        job: ReservedJob,
+        ctx: JobContext,
+    ) -> QResult<ExecutionOutcome> {
+        let Ok(task) = serde_json::from_str::<Box<dyn AsyncJobTrait>>(&job.message) else {
+            return blocking(&self.inner, move |queue| {
+                queue.handle_message_with(&job, &ctx)
+            })
+            .await;
+        };
+        let concurrency_key = task.concurrency_key();
+        let bucket = task.rate_limit_bucket().map(str::to_string);
+        let (job, ctx, execution) = match blocking(&self.inner, move |queue| {
+            let ctx = queue.job_context(&job, &ctx);
+            if let Some(outcome) = queue.intercept(&job, &ctx)? {
+                return Ok(Err(outcome));
+            }
+            let execution = queue.begin(&job, concurrency_key, bucket.as_deref())?;
+            Ok(Ok((job, ctx, execution)))
+        })
+        .await?
+        {
+            Ok(started) => started,
+            Err(outcome) => return Ok(outcome),
+        };
+        let timeout = self.inner.timeout_of(task.execution_timeout());
+        // awaited in this task, aborting the task cancels the job with it
+        let execution_of = catch_unwind(ambient::within_async(&ctx, task.execute_with(&ctx)));
+        let finished = if timeout > 0 {
+            tokio::time::timeout(Duration::from_secs(timeout as u64), execution_of)
+                .await
+                .unwrap_or_else(|_| {
+                    Ok(Err(QError::new(
+                        ErrorKind::Timeout,
+                        format!("job execution abandoned after {}s", timeout),
+                    )))
+                })
+        } else {
+            execution_of.await
+        };
+        let outcome = match finished {
+            Ok(result) => result.into(),
+            Err(payload) => ExecutionOutcome::Panicked {
+                message: queue::panic_message(payload),
+            },
+        };
+        blocking(&self.inner, move |queue| {
+            queue.conclude(&job, execution, outcome)
+        })
+        .await
+    }
+    /// whether the channel is paused, such as by the bury limit
+    pub async fn paused(&self) -> QResult<bool> {
+        Ok(self.config().await?.paused.unwrap_or(false))
+    }
+    /// read the channel defaults stored in redis
+    pub async fn config(&self) -> QResult<ChannelConfig> {
+        blocking(&self.inner, |queue| queue.config()).await
+    }
+    /// a queue on the same connection with [config] applied to the settings, the jobs
+    /// executing with this one keep its settings
+    pub fn reconfigured(&self, config: &ChannelConfig) -> Self {
+        let mut inner = queue::Queue::clone(&self.inner);
+        inner.apply_config(config);
+        Queue {
+            inner: Arc::new(inner),
+            conn: self.conn.clone(),
+        }
+    }
+    /// the status of a job
+    pub async fn status(&self, message_id: &JobId) -> QResult<u8> {
+        let message_id = message_id.clone();
+        blocking(&self.inner, move |queue| queue.status(&message_id)).await
+    }
+    /// check the eviction policy of the server once, see `EvictionCheck`
+    async fn guard_eviction(&self) -> QResult<()> {
+        if !self.inner.eviction_unchecked() {
+            return Ok(());
+        }
+        blocking(&self.inner, |queue| {
+            queue.guard_eviction(&mut queue.connection()?)
+        })
+        .await
+    }
+    /// refresh the expiry of the channel keys when a retention is set
+    async fn touch(&self) -> QResult<()> {
+        if let Some(pipe) = self.inner.touch_pipeline() {
+            pipe.query_async::<()>(&mut self.conn.clone()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A worker for async code, it reserves and deletes jobs on the multiplexed connection,
+/// awaits `AsyncJobTrait` jobs on the runtime of the worker and executes the other jobs
+/// on tokio's blocking pool, so a listening task does not hold a thread while it waits.
+/// The executions are tasks of a `JoinSet` owned by `listen`, none outlives the worker,
+/// each named after its channel and job type for tokio-console
+#[derive(Debug)]
+pub struct QueueTask {
+    queue: Queue,
+    /// how long the worker sleeps when no job was fetched or an error occurred
+    poll_interval: Duration,
+    /// seconds `reserve` polls for a job, 0 checks once
+    block_timeout: u64,
+    /// what happens to jobs whose payload does not deserialize
+    decode_failure: DecodeFailure,
+    /// the number of jobs executing at the same time, unless the channel config sets it
+    concurrency: usize,
+    /// seconds between reloads of the channel config while listening, 0 disables reloading
+    config_interval: u64,
+    /// how long `listen` waits for the executing jobs once stopped before aborting them
+    shutdown_timeout: Duration,
+    /// whether the worker shows its state in the process title
+    process_title: bool,
+    /// set by `stop` to end `listen`
+    stopping: watch::Sender<bool>,
+    /// values shared with the jobs
+    state: AppState,
+    /// called when a job task ends
+    metrics: Option<MetricsHook>,
+}
+
+/// A job task of `QueueTask::listen` which ended, handed to the hook set with
+/// `QueueTask::metrics` for runtime dashboards
+#[derive(Debug)]
+pub struct TaskMetrics<'a> {
+    /// the name of the task, the channel and the job type such as `emails:SendEmail`
+    pub task: &'a str,
+    pub job_id: &'a JobId,
+    /// how long the task ran, from its spawn until the job was settled
+    pub elapsed: Duration,
+    pub failed: bool,
+    /// the job tasks of the worker still executing
+    pub in_flight: usize,
+    /// the metrics of the runtime the worker runs on
+    pub runtime: RuntimeMetrics,
+}
+
+/// The hook of `QueueTask::metrics`
+#[derive(Clone)]
+struct MetricsHook(Arc<dyn Fn(&TaskMetrics) + Send + Sync>);
+
+impl std::fmt::Debug for MetricsHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MetricsHook")
+    }
+}
+
+/// How a job task of `listen` ended
+struct Executed {
+    task: String,
+    job_id: JobId,
+    elapsed: Duration,
+    /// whether the job counts as processed, a released job does not
+    counted: bool,
+    failed: bool,
+    result: QResult<()>,
+}
+
+/// the name of the task executing [job], such as `emails:SendEmail`
+fn task_name(channel: &str, job: &ReservedJob) -> String {
+    let job_type = envelope::job_type(&job.message);
+    format!("{}:{}", channel, job_type.as_deref().unwrap_or("untyped"))
+}
+
+impl QueueTask {
+    pub fn new(queue: Queue) -> Self {
+        QueueTask {
+            queue,
+            poll_interval: Duration::from_millis(1000),
+            block_timeout: 0,
+            decode_failure: DecodeFailure::Park,
+            concurrency: 1,
+            config_interval: 5,
+            shutdown_timeout: Duration::from_secs(30),
+            process_title: false,
+            stopping: watch::Sender::new(false),
+            state: AppState::default(),
+            metrics: None,
+        }
+    }
+    /// register a value shared by every job this task executes, jobs read it with
+    /// `JobContext::get` in `execute_with`
+    pub fn state<T: Any + Send + Sync>(&mut self, value: T) -> &mut Self {
+        self.state.insert(value);
+        self
+    }
+    /// set how long the worker sleeps when no job was fetched or an error occurred
+    pub fn poll_interval(&mut self, interval: Duration) -> &mut Self {
+        self.poll_interval = interval;
+        self
+    }
+    /// set the seconds `reserve` polls for a job before giving up, 0 checks once
+    pub fn block_timeout(&mut self, seconds: u64) -> &mut Self {
+        self.block_timeout = seconds;
+        self
+    }
+    /// set what happens to jobs whose payload does not deserialize
+    pub fn decode_failure(&mut self, policy: DecodeFailure) -> &mut Self {
+        self.decode_failure = policy;
+        self
+    }
+    /// set the number of jobs executing at the same time, the worker reserves the next
+    /// job once one of them ends, 1 by default
+    pub fn concurrency(&mut self, jobs: usize) -> &mut Self {
+        self.concurrency = jobs.max(1);
+        self
+    }
+    /// set the seconds between reloads of the channel config while listening, so the pause
+    /// state, rate limits and concurrency changed in redis apply without a restart
+    pub fn config_interval(&mut self, seconds: u64) -> &mut Self {
+        self.config_interval = seconds;
+        self
+    }
+    /// show the state of the worker as the title of its process, such as
+    /// `queue-rs: emails [3 busy/5] last=SendEmail`, see `task::QueueTask::process_title`
+    pub fn process_title(&mut self, enabled: bool) -> &mut Self {
+        self.process_title = enabled;
+        self
+    }
+    /// reload the channel config into [queue] and [concurrency] and return whether the
+    /// channel is paused, [otherwise] if the config could not be read
+    async fn reload(&self, queue: &mut Queue, concurrency: &mut usize, otherwise: bool) -> bool {
+        match queue.config().await {
+            Ok(config) => {
+                *queue = queue.reconfigured(&config);
+                *concurrency = config
+                    .concurrency
+                    .map_or(self.concurrency, |jobs| (jobs as usize).max(1));
+                config.paused.unwrap_or(false)
+            }
+            Err(e) => {
+                error!("{}", e);
+                otherwise
+            }
+        }
+    }
+    /// set how long `listen` waits for the executing jobs once stopped, the jobs still
+    /// executing then are aborted and delivered again when their ttr expires, 30s by default
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+    /// set a hook called with the task name, duration and runtime metrics of every job task
+    /// once it ends, to feed runtime dashboards
+    pub fn metrics<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&TaskMetrics) + Send + Sync + 'static,
+    {
+        self.metrics = Some(MetricsHook(Arc::new(hook)));
+        self
+    }
+    /// stop `listen`, it reserves no more jobs and waits for the executing ones
+    pub fn stop(&self) {
+        self.stopping.send_replace(true);
+    }
+    /// fetch all jobs and execute them, errors are logged and the loop goes on
+    /// until `stop` is called
+    pub async fn listen(&self) -> WorkerReport {
+        let started = Instant::now();
+        let mut report = WorkerReport::start();
+        let mut state = self.state.clone();
+        state.insert(Dispatcher::new(self.queue.blocking().clone()));
+        let ctx = JobContext::new(state);
+        let mut stopped = self.stopping.subscribe();
+        let mut running = JoinSet::new();
+        let mut paused = false;
+        let mut queue = self.queue.clone();
+        let mut concurrency = self.concurrency;
+        let config_interval = Duration::from_secs(self.config_interval);
+        let mut reloaded_at: Option<Instant> = None;
+        let mut title = self
+            .process_title
+            .then(|| ProcessTitle::new(self.queue.inner.name()));
+        'listen: loop {
+            let mut failed = false;
+            while let Some(joined) = ended(&mut running).await {
+                failed |= self.finished(joined, running.len(), &mut report);
+            }
+            if let Some(title) = &mut title {
+                title.show(running.len(), concurrency);
+            }
+            // the failures may have buried jobs past the bury limit
+            let due = !config_interval.is_zero()
+                && reloaded_at.is_none_or(|at| at.elapsed() >= config_interval);
+            if failed || due {
+                paused = self.reload(&mut queue, &mut concurrency, false).await;
+                reloaded_at = Some(Instant::now());
+            }
+            // wait for a free slot or for the channel to resume
+            while paused || running.len() >= concurrency {
+                tokio::select! {
+                    _ = until_stopped(&mut stopped) => break 'listen,
+                    Some(joined) = running.join_next() => {
+                        if self.finished(joined, running.len(), &mut report) {
+                            paused = self.reload(&mut queue, &mut concurrency, false).await;
+                        }
+                        if let Some(title) = &mut title {
+                            title.show(running.len(), concurrency);
+                        }
+                    }
+                    _ = tokio::time::sleep(self.poll_interval), if paused => {
+                        paused = self.reload(&mut queue, &mut concurrency, true).await;
+                    }
+                }
+            }
+            if *stopped.borrow() {
+                break;
+            }
+            // a reserve is not cancelled midway, it could lose the job it popped
+            let result = match queue.reserve(self.block_timeout).await {
+                Ok(job) => {
+                    if let Some(title) = &mut title {
+                        title.started(&job);
+                        title.show(running.len() + 1, concurrency);
+                    }
+                    let task = task_name(queue.inner.name(), &job);
+                    let span = info_span!("job", task = %task, id = %job.id);
+                    let (queue, ctx) = (queue.clone(), ctx.clone());
+                    let execution =
+                        Self::execute(queue, job, ctx, self.decode_failure, task.clone());
+                    spawn_named(&mut running, &task, execution.instrument(span));
+                    Ok(())
+                }
+                // the reserve already polled for a job
+                Err(e) if e.kind() == ErrorKind::NotFound && self.block_timeout > 0 => continue,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    debug!("{}", e);
+                    Err(e)
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    Err(e)
+                }
+            };
+            if result.is_err() {
+                tokio::select! {
+                    _ = until_stopped(&mut stopped) => break,
+                    _ = tokio::time::sleep(self.poll_interval) => {}
+                }
+            }
+        }
+        self.shutdown(running, &mut report).await;
+        info!(
+            "Async worker of channel [{}] stopped",
+            self.queue.inner.name()
+        );
+        report.finish(started, ExitCause::Stopped)
+    }
+    /// wait for the executing jobs until the shutdown timeout, then abort the others
+    async fn shutdown(&self, mut running: JoinSet<Executed>, report: &mut WorkerReport) {
+        let deadline = tokio::time::sleep(self.shutdown_timeout);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                joined = running.join_next() => match joined {
+                    Some(joined) => {
+                        self.finished(joined, running.len(), report);
+                    }
+                    None => return,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        warn!(
+            "Async worker of channel [{}] aborted [{}] jobs still executing after {:?}",
+            self.queue.inner.name(),
+            running.len(),
+            self.shutdown_timeout
+        );
+        running.shutdown().await;
+    }
+    /// count a job task in [report] and call the metrics hook, return whether the job failed
+    fn finished(
+        &self,
+        joined: Result<Executed, JoinError>,
+        in_flight: usize,
+        report: &mut WorkerReport,
+    ) -> bool {
+        let executed = match joined {
+            Ok(executed) => executed,
+            Err(e) => {
+                error!("job task ended abnormally: {}", e);
+                report.processed += 1;
+                report.failed += 1;
+                return true;
+            }
+        };
+        if executed.counted {
+            report.processed += 1;
+        }
+        if executed.failed {
+            report.failed += 1;
+        }
+        match &executed.result {
+            Err(e) if executed.counted => error!("{}", e),
+            Err(e) => debug!("{}", e),
+            Ok(()) => {}
+        }
+        if let Some(MetricsHook(hook)) = &self.metrics {
+            hook(&TaskMetrics {
+                task: &executed.task,
+                job_id: &executed.job_id,
+                elapsed: executed.elapsed,
+                failed: executed.failed,
+                in_flight,
+                runtime: tokio::runtime::Handle::current().metrics(),
+            });
+        }
+        executed.failed
+    }
+    /// execute a reserved job and settle it
+    async fn execute(
+        queue: Queue,
+        job: ReservedJob,
+        ctx: JobContext,
+        decode_failure: DecodeFailure,
+        task: String,
+    ) -> Executed {
+        let started = Instant::now();
+        let job_id = job.id.clone();
+        let handled = queue.handle_message(job.clone(), ctx).await;
+        let (counted, failed) = match &handled {
+            Err(e) if matches!(e.kind(), ErrorKind::RateLimited | ErrorKind::Locked) => {
+                (false, false)
+            }
+            Err(_) => (true, true),
+            Ok(outcome) => (true, outcome.is_failure()),
+        };
+        let result = match handled {
+            Err(e) if e.kind() == ErrorKind::JsonConvert => {
+                blocking(&queue.inner, move |queue| {
+                    undecodable(queue, &job, decode_failure, e)
+                })
+                .await
+            }
+            Err(e) => Err(e),
+            Ok(outcome) => queue.settle(job, outcome).await.map(|_| ()),
+        };
+        Executed {
+            task,
+            job_id,
+            elapsed: started.elapsed(),
+            counted,
+            failed,
+            result,
+        }
+    }
+}
+
+/// spawn a job task, named for tokio-console when built with `--cfg tokio_unstable`
+fn spawn_named<F>(running: &mut JoinSet<Executed>, name: &str, task: F)
+where
+    F: Future<Output = Executed> + Send + 'static,
+{
+    #[cfg(tokio_unstable)]
+    if let Err(e) = running.build_task().name(name).spawn(task) {
+        error!("job task [{}] not spawned: {}", name, e);
+    }
+    #[cfg(not(tokio_unstable))]
+    {
+        let _ = name;
+        running.spawn(task);
+    }
+}
+
+/// wait until `QueueTask::stop` is called
+async fn until_stopped(stopped: &mut watch::Receiver<bool>) {
+    let _ = stopped.wait_for(|stopped| *stopped).await;
+}
+
+/// the result of a job task which already ended, without waiting for the others
+async fn ended(running: &mut JoinSet<Executed>) -> Option<Result<Executed, JoinError>> {
+    tokio::select! {
+        biased;
+        Some(joined) = running.join_next() => Some(joined),
+        _ = std::future::ready(()) => None,
+    }
+}
+
+// test async
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Serialize, Deserialize)]
+    struct AsyncJob {
+        title: String,
+    }
+    #[typetag::serde]
+    impl JobTrait for AsyncJob {
+        fn execute(&self) -> QResult<()> {
+            println!("async job [{}] executed", self.title);
+            Ok(())
+        }
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    // test an unreachable redis is an error
+    #[test]
+    fn test_async_unreachable() {
+        let queue = queue::Queue::new("test", redis::Client::open("redis://127.0.0.1:1/").unwrap());
+        let Err(e) = runtime().block_on(Queue::new(queue)) else {
+            panic!("connected without redis");
+        };
+        assert_eq!(e.kind(), ErrorKind::Redis);
+    }
+    // test push, reserve and delete on the multiplexed connection
+    #[test]
+    fn test_async_queue() {
+        runtime().block_on(async {
+            let mut queue = queue::Queue::new(
+                "test_async",
+                redis::Client::open("redis://127.0.0.1/").unwrap(),
+            );
+            queue.ttl(60);
+            queue.clear().unwrap();
+            let queue = Queue::new(queue).await.unwrap();
+            let id = queue
+                .push(AsyncJob {
+                    title: "async".to_string(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(queue.status(&id).await.unwrap(), queue::STATUS_WAITING);
+            let job = queue.reserve(1).await.unwrap();
+            assert_eq!(job.id, id);
+            assert_eq!(job.ttr, 60);
+            let outcome = queue
+                .handle_message(job.clone(), JobContext::default())
+                .await;
+            assert!(outcome.unwrap().is_success());
+            assert!(!queue.delete(&job.id, "stale").await.unwrap());
+            assert!(queue.delete(&job.id, &job.token).await.unwrap());
+            assert_eq!(queue.status(&id).await.unwrap(), queue::STATUS_DONE);
+            let Err(e) = queue.reserve(0).await else {
+                panic!("reserved from an empty channel");
+            };
+            assert_eq!(e.kind(), ErrorKind::NotFound);
+        });
+    }
+    // test async jobs are awaited on the runtime of the worker and abandoned on timeout
+    #[test]
+    fn test_async_native() {
+        use crate::job::JobFuture;
+        use std::sync::Mutex;
+        use std::thread::{self, ThreadId};
+        static EXECUTED: Mutex<Option<(ThreadId, Option<String>)>> = Mutex::new(None);
+        #[derive(Serialize, Deserialize)]
+        struct Sleep {
+            ms: u64,
+        }
+        #[typetag::serde]
+        impl AsyncJobTrait for Sleep {
+            fn execute(&self) -> JobFuture<'_> {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(self.ms)).await;
+                    *EXECUTED.lock().unwrap() =
+                        Some((thread::current().id(), ambient::get("locale")));
+                    Ok(())
+                })
+            }
+            fn execution_timeout(&self) -> Option<u32> {
+                Some(1)
+            }
+        }
+        runtime().block_on(async {
+            let queue = queue::Queue::new(
+                "test_async_native",
+                redis::Client::open("redis://127.0.0.1/").unwrap(),
+            );
+            queue.clear().unwrap();
+            let queue = Queue::new(queue).await.unwrap();
+            ambient::set("locale", "de");
+            queue.push_async_job(Sleep { ms: 10 }).await.unwrap();
+            queue.push_async_job(Sleep { ms: 1500 }).await.unwrap();
+            ambient::remove("locale");
+            // a current thread runtime polls its tasks on this thread, not the blocking pool
+            let job = queue.reserve(1).await.unwrap();
+            let outcome = queue.handle_message(job, JobContext::default()).await;
+            assert!(outcome.unwrap().is_success());
+            let executed = EXECUTED.lock().unwrap().take();
+            assert_eq!(
+                executed,
+                Some((thread::current().id(), Some("de".to_string())))
+            );
+            let job = queue.reserve(1).await.unwrap();
+            let started = Instant::now();
+            let outcome = queue.handle_message(job, JobContext::default()).await;
+            let Ok(ExecutionOutcome::Failed { error }) = outcome else {
+                panic!("the slow job was not abandoned");
+            };
+            assert_eq!(error.kind(), ErrorKind::Timeout);
+            assert!(started.elapsed() < Duration::from_millis(1400));
+            tokio::time::sleep(Duration::from_millis(1000)).await;
+            assert!(EXECUTED.lock().unwrap().is_none());
+        });
+    }
+
+    // test job tasks are named after the channel and the job type
+    #[test]
+    fn test_task_name() {
+        let mut job = ReservedJob {
+            id: "1".parse().unwrap(),
+            message: "{\"type\":\"SendEmail\",\"to\":\"a@b.c\"}".to_string(),
+            ttr: 60,
+            attempts: 1,
+            token: "token".to_string(),
+            metadata: Default::default(),
+        };
+        assert_eq!(task_name("emails", &job), "emails:SendEmail");
+        job.message = "[1, 2]".to_string();
+        assert_eq!(task_name("emails", &job), "emails:untyped");
+    }
+
+    /// the jobs of the listen tests executing now, the most seen at once and the ones
+    /// cancelled before they ended
+    static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+    static MOST_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+    static CANCELLED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Serialize, Deserialize)]
+    struct Tracked {
+        ms: u64,
+    }
+    #[typetag::serde]
+    impl AsyncJobTrait for Tracked {
+        fn execute(&self) -> crate::job::JobFuture<'_> {
+            struct Guard(bool);
+            impl Drop for Guard {
+                fn drop(&mut self) {
+                    IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+                    if !self.0 {
+                        CANCELLED.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            }
+            Box::pin(async move {
+                let in_flight = IN_FLIGHT.fetch_add(1, Ordering::SeqCst) + 1;
+                MOST_IN_FLIGHT.fetch_max(in_flight, Ordering::SeqCst);
+                let mut guard = Guard(false);
+                tokio::time::sleep(Duration::from_millis(self.ms)).await;
+                guard.0 = true;
+                Ok(())
+            })
+        }
+    }
+
+    async fn tracked_task(channel: &str, jobs: &[u64]) -> QueueTask {
+        let queue = queue::Queue::new(channel, redis::Client::open("redis://127.0.0.1/").unwrap());
+        queue.clear().unwrap();
+        let queue = Queue::new(queue).await.unwrap();
+        for ms in jobs {
+            queue.push_async_job(Tracked { ms: *ms }).await.unwrap();
+        }
+        let mut task = QueueTask::new(queue);
+        task.poll_interval(Duration::from_millis(10));
+        task
+    }
+    // test listen executes at most the concurrency of jobs at once and waits for them
+    #[test]
+    fn test_async_concurrency() {
+        runtime().block_on(async {
+            let mut task = tracked_task("test_async_concurrency", &[200; 5]).await;
+            let ended = Arc::new(std::sync::Mutex::new(Vec::new()));
+            task.concurrency(2).metrics({
+                let ended = Arc::clone(&ended);
+                move |metrics| {
+                    assert!(metrics.in_flight < 2);
+                    ended.lock().unwrap().push(metrics.task.to_string());
+                }
+            });
+            let task = Arc::new(task);
+            let listening = tokio::spawn({
+                let task = Arc::clone(&task);
+                async move { task.listen().await }
+            });
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            task.stop();
+            let report = listening.await.unwrap();
+            assert_eq!(report.processed, 5);
+            assert_eq!(report.failed, 0);
+            assert_eq!(MOST_IN_FLIGHT.load(Ordering::SeqCst), 2);
+            assert_eq!(
+                *ended.lock().unwrap(),
+                vec!["test_async_concurrency:Tracked"; 5]
+            );
+        });
+    }
+    // test listen applies the concurrency changed in the channel config without a restart
+    #[test]
+    fn test_async_reload_concurrency() {
+        #[derive(Serialize, Deserialize)]
+        struct Sleeping {
+            ms: u64,
+        }
+        #[typetag::serde]
+        impl AsyncJobTrait for Sleeping {
+            fn execute(&self) -> crate::job::JobFuture<'_> {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(self.ms)).await;
+                    Ok(())
+                })
+            }
+        }
+        runtime().block_on(async {
+            let channel = "test_async_reload_concurrency";
+            let queue =
+                queue::Queue::new(channel, redis::Client::open("redis://127.0.0.1/").unwrap());
+            queue.clear().unwrap();
+            let queue = Queue::new(queue).await.unwrap();
+            for _ in 0..3 {
+                queue.push_async_job(Sleeping { ms: 300 }).await.unwrap();
+            }
+            let config = ChannelConfig {
+                concurrency: Some(3),
+                ..Default::default()
+            };
+            queue.blocking().save_config(&config).unwrap();
+            let most = Arc::new(AtomicUsize::new(0));
+            let mut task = QueueTask::new(queue.clone());
+            task.poll_interval(Duration::from_millis(10)).metrics({
+                let most = Arc::clone(&most);
+                move |metrics| {
+                    most.fetch_max(metrics.in_flight + 1, Ordering::SeqCst);
+                }
+            });
+            let task = Arc::new(task);
+            let listening = tokio::spawn({
+                let task = Arc::clone(&task);
+                async move { task.listen().await }
+            });
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            task.stop();
+            let report = listening.await.unwrap();
+            assert_eq!(report.processed, 3);
+            // the three jobs ran at once instead of the one of the task
+            assert_eq!(most.load(Ordering::SeqCst), 3);
+            queue.blocking().clear().unwrap();
+        });
+    }
+    // test the jobs still executing at the shutdown deadline are cancelled
+    #[test]
+    fn test_async_shutdown() {
+        runtime().block_on(async {
+            let mut task = tracked_task("test_async_shutdown", &[5000]).await;
+            task.shutdown_timeout(Duration::from_millis(200));
+            let task = Arc::new(task);
+            let listening = tokio::spawn({
+                let task = Arc::clone(&task);
+                async move { task.listen().await }
+            });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            task.stop();
+            let started = Instant::now();
+            let report = listening.await.unwrap();
+            assert!(started.elapsed() < Duration::from_secs(1));
+            assert_eq!(report.processed, 0);
+            assert_eq!(CANCELLED.load(Ordering::SeqCst), 1);
+            assert_eq!(IN_FLIGHT.load(Ordering::SeqCst), 0);
+        });
+    }
+}
@@ -0,0 +1,602 @@
+use crate::config::ChannelConfig;
+use crate::error::{ErrorKind, QError};
+use crate::id::JobId;
+use crate::job::{JobContext, JobTrait};
+use crate::queue::{
+    panic_message, Dispatcher, ExecutionOutcome, ReservedJob, STATUS_DONE, STATUS_RESERVED,
+    STATUS_WAITING,
+};
+use crate::{err, timestamp, QResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::error;
+
+/// The storage operations a queue needs to push and work jobs. `Queue` implements it on
+/// redis, other stores such as a database or a file implement it to run the same jobs.
+/// The provided methods are how `QueueTask` works the jobs of a backend, a backend
+/// overrides them for what it keeps beyond the jobs, such as buried jobs or a config
+pub trait QueueBackend: Send + Sync + Debug {
+    /// store a serialized job and make it available, return its id
+    fn push_message(&self, message: String) -> QResult<JobId>;
+    /// take the next waiting job for its ttr, a `NotFound` error when there is none
+    fn reserve(&self, timeout: u64) -> QResult<ReservedJob>;
+    /// delete a reserved job, false if [token] no longer holds the reservation
+    fn delete(&self, id: &JobId, token: &str) -> QResult<bool>;
+    /// move the due delayed jobs and the expired reservations back to waiting
+    fn move_expired(&self) -> QResult<()>;
+    /// one of the STATUS_* constants
+    fn status(&self, id: &JobId) -> QResult<u8>;
+    /// remove every job
+    fn clear(&self) -> QResult<()>;
+    /// the channel of the queue, empty for a backend without channels
+    fn name(&self) -> &str {
+        ""
+    }
+    /// Push a job to the queue
+    fn push<T: JobTrait + Serialize>(&self, job: T) -> QResult<JobId>
+    where
+        Self: Sized,
+    {
+        let job = &job as &dyn JobTrait;
+        job.validate()?;
+        self.push_message(serde_json::to_string(job)?)
+    }
+    /// execute a reserved job, an error means the job could not be deserialized
+    fn handle_message_with(
+        &self,
+        job: &ReservedJob,
+        ctx: &JobContext,
+    ) -> QResult<ExecutionOutcome> {
+        let job: Box<dyn JobTrait> = serde_json::from_str(&job.message)?;
+        let outcome = match panic::catch_unwind(AssertUnwindSafe(|| job.execute_with(ctx))) {
+            Ok(result) => result.into(),
+            Err(payload) => ExecutionOutcome::Panicked {
+                message: panic_message(payload),
+            },
+        };
+        Ok(outcome)
+    }
+    /// settle a reserved job after its execution, deleted whatever its outcome unless the
+    /// backend retries failures. Return false if the reservation has expired
+    fn settle(&self, job: &ReservedJob, _outcome: &ExecutionOutcome) -> QResult<bool> {
+        self.delete(&job.id, &job.token)
+    }
+    /// bury a job so it stops being delivered, false when the backend cannot bury, the job
+    /// is then delivered again once its ttr expired
+    fn bury(&self, _id: &JobId) -> QResult<bool> {
+        Ok(false)
+    }
+    /// the channel config workers reload while listening, the defaults for a backend
+    /// without one
+    fn config(&self) -> QResult<ChannelConfig> {
+        Ok(ChannelConfig::default())
+    }
+    /// apply a reloaded channel config
+    fn apply_config(&mut self, _config: &ChannelConfig) {}
+    /// the dispatcher running jobs push their follow-up jobs with, also keeping the
+    /// heartbeats of the workers, `None` for a backend without one
+    fn dispatcher(&self) -> Option<Dispatcher> {
+        None
+    }
+    /// execute and settle the waiting jobs until none is left, return the outcomes in
+    /// execution order
+    fn run_waiting(&self, ctx: &JobContext) -> QResult<Vec<(JobId, ExecutionOutcome)>> {
+        let mut outcomes = Vec::new();
+        loop {
+            let job = match self.reserve(0) {
+                Ok(job) => job,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(outcomes),
+                Err(e) => return Err(e),
+            };
+            let outcome = self.handle_message_with(&job, ctx)?;
+            self.settle(&job, &outcome)?;
+            outcomes.push((job.id, outcome));
+        }
+    }
+}
+
+/// A queue keeping jobs in the memory of the process, so job pipelines can be unit
+/// tested without a redis server, or for jobs which may be lost when the process exits.
+/// `reserve` does not block
+#[derive(Debug, Default)]
+pub struct MemoryQueue {
+    /// the seconds a reservation lasts
+    ttr: u32,
+    /// the seconds a pushed job waits before it can be reserved
+    delay: u32,
+    jobs: Mutex<MemoryJobs>,
+    /// the snapshot saved when the queue is dropped, set by `load`
+    snapshot: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryJobs {
+    next_id: u64,
+    messages: HashMap<JobId, String>,
+    waiting: VecDeque<JobId>,
+    /// (due time, id)
+    delayed: BTreeSet<(u64, JobId)>,
+    /// id => (expire time, token)
+    reserved: HashMap<JobId, (u64, String)>,
+    attempts: HashMap<JobId, u32>,
+    /// the file every change is appended to before it is applied, see `FileQueue`
+    journal: Option<File>,
+}
+
+/// A change of the jobs, a line of the `FileQueue` journal
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Change {
+    /// a job is stored, waiting or delayed until [due] when it is not 0
+    Push {
+        id: JobId,
+        due: u64,
+        message: String,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        attempts: u32,
+    },
+    Reserve {
+        id: JobId,
+        token: String,
+        expire: u64,
+    },
+    Delete {
+        id: JobId,
+    },
+    /// a delayed job is due or a reservation expired
+    Requeue {
+        id: JobId,
+    },
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+impl MemoryJobs {
+    /// append [change] to the journal if there is one, then apply it
+    fn record(&mut self, change: Change) -> QResult<()> {
+        if let Some(journal) = &mut self.journal {
+            let mut line = serde_json::to_string(&change)?;
+            line.push('\n');
+            journal.write_all(line.as_bytes()).map_err(io_error)?;
+            journal.sync_data().map_err(io_error)?;
+        }
+        self.apply(change);
+        Ok(())
+    }
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Push {
+                id,
+                due,
+                message,
+                attempts,
+            } => {
+                if let Ok(number) = id.to_string().parse::<u64>() {
+                    self.next_id = self.next_id.max(number);
+                }
+                if attempts > 0 {
+                    self.attempts.insert(id.clone(), attempts);
+                }
+                self.messages.insert(id.clone(), message);
+                if due > 0 {
+                    self.delayed.insert((due, id));
+                } else {
+                    self.waiting.push_back(id);
+                }
+            }
+            Change::Reserve { id, token, expire } => {
+                if let Some(pos) = self.waiting.iter().position(|waiting| *waiting == id) {
+                    self.waiting.remove(pos);
+                }
+                *self.attempts.entry(id.clone()).or_default() += 1;
+                self.reserved.insert(id, (expire, token));
+            }
+            Change::Delete { id } => {
+                self.reserved.remove(&id);
+                self.messages.remove(&id);
+                self.attempts.remove(&id);
+            }
+            Change::Requeue { id } => {
+                self.delayed.retain(|(_, delayed)| *delayed != id);
+                self.reserved.remove(&id);
+                self.waiting.push_back(id);
+            }
+        }
+    }
+    /// replay the journal or snapshot at [path], no jobs if it does not exist
+    fn read(path: &Path) -> QResult<Self> {
+        let mut jobs = MemoryJobs::default();
+        match File::open(path) {
+            Ok(file) => {
+                let mut lines = BufReader::new(file).lines().peekable();
+                while let Some(line) = lines.next() {
+                    let line = line.map_err(io_error)?;
+                    match serde_json::from_str(&line) {
+                        Ok(change) => jobs.apply(change),
+                        // a line cut by a crash is the last one, its change never applied
+                        Err(_) if lines.peek().is_none() => {}
+                        Err(e) => {
+                            return err!(
+                                ErrorKind::InvalidPayload,
+                                "invalid journal line in [{}]: {}",
+                                path.display(),
+                                e
+                            )
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(io_error(e)),
+        }
+        Ok(jobs)
+    }
+    /// replace the file at [path] with the snapshot of the jobs, through a renamed
+    /// temporary file so a crash leaves the old or the new one
+    fn write(&self, path: &Path) -> QResult<()> {
+        let mut content = String::new();
+        for change in self.snapshot() {
+            content.push_str(&serde_json::to_string(&change)?);
+            content.push('\n');
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).map_err(io_error)?;
+        fs::rename(&tmp, path).map_err(io_error)?;
+        Ok(())
+    }
+    /// the changes recreating the current jobs, waiting jobs keep their order
+    fn snapshot(&self) -> Vec<Change> {
+        let push = |id: &JobId, due: u64| Change::Push {
+            id: id.clone(),
+            due,
+            message: self.messages.get(id).cloned().unwrap_or_default(),
+            attempts: self.attempts.get(id).copied().unwrap_or(0),
+        };
+        let mut changes: Vec<Change> = self.waiting.iter().map(|id| push(id, 0)).collect();
+        changes.extend(self.delayed.iter().map(|(due, id)| push(id, *due)));
+        for (id, (expire, token)) in &self.reserved {
+            // the reserve change counts the attempt again
+            let mut change = push(id, 0);
+            if let Change::Push { attempts, .. } = &mut change {
+                *attempts = attempts.saturating_sub(1);
+            }
+            changes.push(change);
+            changes.push(Change::Reserve {
+                id: id.clone(),
+                token: token.clone(),
+                expire: *expire,
+            });
+        }
+        changes
+    }
+}
+
+impl MemoryQueue {
+    pub fn new(ttr: u32) -> Self {
+        MemoryQueue {
+            ttr,
+            delay: 0,
+            jobs: Mutex::default(),
+            snapshot: None,
+        }
+    }
+    /// Load the jobs of the snapshot at [path], saved by `save` or a `FileQueue` journal,
+    /// none if it does not exist. The jobs are saved back to [path] when the queue is
+    /// dropped, so they survive a graceful restart but not a crash
+    pub fn load(path: impl AsRef<Path>, ttr: u32) -> QResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        Ok(MemoryQueue {
+            ttr,
+            delay: 0,
+            jobs: Mutex::new(MemoryJobs::read(&path)?),
+            snapshot: Some(path),
+        })
+    }
+    /// Save the jobs to [path], reserved jobs are saved with their reservation
+    pub fn save(&self, path: impl AsRef<Path>) -> QResult<()> {
+        self.jobs.lock().unwrap().write(path.as_ref())
+    }
+    /// Set the seconds a pushed job waits before it can be reserved
+    pub fn delay(&mut self, delay: u32) -> &mut Self {
+        self.delay = delay;
+        self
+    }
+}
+
+impl QueueBackend for MemoryQueue {
+    fn push_message(&self, message: String) -> QResult<JobId> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = JobId::from(jobs.next_id + 1);
+        let due = match self.delay {
+            0 => 0,
+            delay => timestamp()? + delay as u64,
+        };
+        jobs.record(Change::Push {
+            id: id.clone(),
+            due,
+            message,
+            attempts: 0,
+        })?;
+        Ok(id)
+    }
+    fn reserve(&self, _timeout: u64) -> QResult<ReservedJob> {
+        self.move_expired()?;
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(id) = jobs.waiting.front().cloned() else {
+            return err!(ErrorKind::NotFound, "No job found");
+        };
+        let Some(message) = jobs.messages.get(&id).cloned() else {
+            return err!(ErrorKind::NotFound, "missing message of job id:[{}]", id);
+        };
+        let token = ulid::Ulid::new().to_string();
+        jobs.record(Change::Reserve {
+            id: id.clone(),
+            token: token.clone(),
+            expire: timestamp()? + self.ttr as u64,
+        })?;
+        let attempts = jobs.attempts.get(&id).copied().unwrap_or(1);
+        Ok(ReservedJob {
+            id,
+            message,
+            ttr: self.ttr,
+            attempts,
+            token,
+            metadata: BTreeMap::new(),
+        })
+    }
+    fn delete(&self, id: &JobId, token: &str) -> QResult<bool> {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.reserved.get(id) {
+            Some((_, held)) if held == token => {}
+            _ => return Ok(false),
+        }
+        jobs.record(Change::Delete { id: id.clone() })?;
+        Ok(true)
+    }
+    fn move_expired(&self) -> QResult<()> {
+        let now = timestamp()?;
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut due: Vec<JobId> = jobs
+            .delayed
+            .iter()
+            .take_while(|(due, _)| *due <= now)
+            .map(|(_, id)| id.clone())
+            .collect();
+        due.extend(
+            jobs.reserved
+                .iter()
+                .filter(|(_, (expire, _))| *expire <= now)
+                .map(|(id, _)| id.clone()),
+        );
+        for id in due {
+            jobs.record(Change::Requeue { id })?;
+        }
+        Ok(())
+    }
+    fn status(&self, id: &JobId) -> QResult<u8> {
+        let jobs = self.jobs.lock().unwrap();
+        let status = if jobs.reserved.contains_key(id) {
+            STATUS_RESERVED
+        } else if jobs.messages.contains_key(id) {
+            STATUS_WAITING
+        } else {
+            STATUS_DONE
+        };
+        Ok(status)
+    }
+    fn clear(&self) -> QResult<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let journal = jobs.journal.take();
+        if let Some(journal) = &journal {
+            journal.set_len(0).map_err(io_error)?;
+        }
+        *jobs = MemoryJobs {
+            journal,
+            ..Default::default()
+        };
+        Ok(())
+    }
+}
+
+impl Drop for MemoryQueue {
+    fn drop(&mut self) {
+        if let Some(path) = &self.snapshot {
+            if let Err(e) = self.save(path) {
+                error!("Jobs not saved to [{}]: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// A `MemoryQueue` whose changes are appended to a journal file and synced before they
+/// apply, so its jobs survive restarts where no database is available. Opening the queue
+/// replays the journal and rewrites it with only the jobs left. A single process may
+/// open a journal at a time
+#[derive(Debug)]
+pub struct FileQueue {
+    queue: MemoryQueue,
+    path: PathBuf,
+}
+
+impl FileQueue {
+    /// open the queue journaled at [path], created if it does not exist
+    pub fn open(path: impl AsRef<Path>, ttr: u32) -> QResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut jobs = MemoryJobs::read(&path)?;
+        jobs.write(&path)?;
+        jobs.journal = Some(
+            OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .map_err(io_error)?,
+        );
+        let queue = MemoryQueue {
+            ttr,
+            delay: 0,
+            jobs: Mutex::new(jobs),
+            snapshot: None,
+        };
+        Ok(FileQueue { queue, path })
+    }
+    /// the journal file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Set the seconds a pushed job waits before it can be reserved
+    pub fn delay(&mut self, delay: u32) -> &mut Self {
+        self.queue.delay(delay);
+        self
+    }
+}
+
+impl QueueBackend for FileQueue {
+    fn push_message(&self, message: String) -> QResult<JobId> {
+        self.queue.push_message(message)
+    }
+    fn reserve(&self, timeout: u64) -> QResult<ReservedJob> {
+        self.queue.reserve(timeout)
+    }
+    fn delete(&self, id: &JobId, token: &str) -> QResult<bool> {
+        self.queue.delete(id, token)
+    }
+    fn move_expired(&self) -> QResult<()> {
+        self.queue.move_expired()
+    }
+    fn status(&self, id: &JobId) -> QResult<u8> {
+        self.queue.status(id)
+    }
+    fn clear(&self) -> QResult<()> {
+        self.queue.clear()
+    }
+}
+
+pub(crate) fn io_error(e: std::io::Error) -> QError {
+    QError::new(ErrorKind::Other, e.to_string())
+}
+
+// test backend
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize)]
+    struct InvoiceJob {
+        amount: u32,
+    }
+    #[typetag::serde]
+    impl JobTrait for InvoiceJob {
+        fn execute(&self) -> QResult<()> {
+            if self.amount == 0 {
+                return err!("empty invoice");
+            }
+            Ok(())
+        }
+    }
+
+    // test the memory backend reserves and deletes in push order
+    #[test]
+    fn test_memory_backend() {
+        let backend = MemoryQueue::new(0);
+        let first = backend
+            .push_message("{\"type\":\"A\"}".to_string())
+            .unwrap();
+        let second = backend
+            .push_message("{\"type\":\"B\"}".to_string())
+            .unwrap();
+        assert_eq!(backend.status(&first).unwrap(), STATUS_WAITING);
+        let job = backend.reserve(0).unwrap();
+        assert_eq!(job.id, first);
+        assert_eq!(backend.status(&first).unwrap(), STATUS_RESERVED);
+        assert!(!backend.delete(&first, "stale").unwrap());
+        // a ttr of 0 expires the reservation right away
+        backend.move_expired().unwrap();
+        assert_eq!(backend.reserve(0).unwrap().id, second);
+        let job = backend.reserve(0).unwrap();
+        assert_eq!((job.id.clone(), job.attempts), (first.clone(), 2));
+        assert!(backend.delete(&job.id, &job.token).unwrap());
+        assert_eq!(backend.status(&first).unwrap(), STATUS_DONE);
+        backend.clear().unwrap();
+        let e = backend.reserve(0).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+    // test jobs run in memory without redis
+    #[test]
+    fn test_memory_run() {
+        let queue = MemoryQueue::new(60);
+        let paid = queue.push(InvoiceJob { amount: 12 }).unwrap();
+        let empty = queue.push(InvoiceJob { amount: 0 }).unwrap();
+        let outcomes = queue.run_waiting(&JobContext::default()).unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].0, paid);
+        assert!(outcomes[0].1.is_success());
+        assert_eq!(outcomes[1].0, empty);
+        assert!(outcomes[1].1.is_failure());
+        assert_eq!(queue.status(&paid).unwrap(), STATUS_DONE);
+    }
+    // test a loaded memory queue saves its jobs when dropped
+    #[test]
+    fn test_memory_snapshot() {
+        let path = std::env::temp_dir().join(format!("queue-rs-snapshot-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let queue = MemoryQueue::load(&path, 60).unwrap();
+        let first = queue.push(InvoiceJob { amount: 12 }).unwrap();
+        let second = queue.push(InvoiceJob { amount: 13 }).unwrap();
+        let job = queue.reserve(0).unwrap();
+        drop(queue);
+
+        let queue = MemoryQueue::load(&path, 60).unwrap();
+        assert_eq!(queue.status(&first).unwrap(), STATUS_RESERVED);
+        assert!(queue.delete(&first, &job.token).unwrap());
+        assert_eq!(queue.status(&second).unwrap(), STATUS_WAITING);
+        drop(queue);
+        // a snapshot opens as a file queue journal
+        let queue = FileQueue::open(&path, 60).unwrap();
+        assert_eq!(queue.status(&first).unwrap(), STATUS_DONE);
+        assert_eq!(queue.reserve(0).unwrap().id, second);
+        drop(queue);
+        fs::remove_file(&path).unwrap();
+    }
+    // test a file queue keeps its jobs across reopening
+    #[test]
+    fn test_file_queue() {
+        let path = std::env::temp_dir().join(format!("queue-rs-journal-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let queue = FileQueue::open(&path, 60).unwrap();
+        let first = queue.push(InvoiceJob { amount: 12 }).unwrap();
+        let second = queue.push(InvoiceJob { amount: 13 }).unwrap();
+        let job = queue.reserve(0).unwrap();
+        assert_eq!(job.id, first);
+        drop(queue);
+
+        let mut queue = FileQueue::open(&path, 60).unwrap();
+        assert_eq!(queue.status(&first).unwrap(), STATUS_RESERVED);
+        assert_eq!(queue.status(&second).unwrap(), STATUS_WAITING);
+        assert!(queue.delete(&first, &job.token).unwrap());
+        queue.delay(600);
+        let delayed = queue.push(InvoiceJob { amount: 14 }).unwrap();
+        assert!(delayed != first && delayed != second);
+        drop(queue);
+        // a change cut by a crash is dropped
+        let mut journal = OpenOptions::new().append(true).open(&path).unwrap();
+        journal.write_all(b"{\"op\":\"pu").unwrap();
+
+        let queue = FileQueue::open(&path, 60).unwrap();
+        assert_eq!(queue.status(&first).unwrap(), STATUS_DONE);
+        let outcomes = queue.run_waiting(&JobContext::default()).unwrap();
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].0, second);
+        assert_eq!(queue.status(&delayed).unwrap(), STATUS_WAITING);
+        queue.clear().unwrap();
+        assert_eq!(queue.status(&delayed).unwrap(), STATUS_DONE);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+use crate::backend::{io_error, QueueBackend};
+use crate::error::ErrorKind;
+use crate::id::JobId;
+use crate::queue::{ReservedJob, STATUS_BURIED, STATUS_DONE, STATUS_RESERVED, STATUS_WAITING};
+use crate::{err, timestamp, QResult};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+
+/// A queue on a beanstalkd tube, so jobs of existing beanstalk infrastructure run as
+/// rust jobs. Each reservation holds its own connection, as beanstalkd ties a reservation
+/// to the connection which reserved it, and the reservation token names that connection.
+/// beanstalkd moves delayed jobs and expired reservations itself, `move_expired` only
+/// closes the connections of expired reservations
+#[derive(Debug)]
+pub struct BeanstalkQueue {
+    /// `host:port` of beanstalkd
+    addr: String,
+    tube: String,
+    /// the seconds a reservation lasts
+    ttr: u32,
+    /// the seconds a pushed job waits before it can be reserved
+    delay: u32,
+    /// the beanstalkd priority of pushed jobs, lower runs first
+    priority: u32,
+    /// the connection pushes and lookups share, opened on first use
+    conn: Mutex<Option<Connection>>,
+    /// token => (expire time, the connection holding the reservation)
+    reservations: Mutex<HashMap<String, (u64, Connection)>>,
+}
+
+/// A connection to beanstalkd using and watching one tube
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(addr: &str, tube: &str) -> QResult<Self> {
+        let writer = TcpStream::connect(addr).map_err(io_error)?;
+        let reader = BufReader::new(writer.try_clone().map_err(io_error)?);
+        let mut conn = Connection { reader, writer };
+        if tube != "default" {
+            conn.expect(&format!("use {}", tube), "USING")?;
+            conn.expect(&format!("watch {}", tube), "WATCHING")?;
+            conn.expect("ignore default", "WATCHING")?;
+        }
+        Ok(conn)
+    }
+    /// send a command line and an optional body, return the words of the response line
+    fn command(&mut self, line: &str, body: Option<&[u8]>) -> QResult<Vec<String>> {
+        let mut request = format!("{}\r\n", line).into_bytes();
+        if let Some(body) = body {
+            request.extend_from_slice(body);
+            request.extend_from_slice(b"\r\n");
+        }
+        self.writer.write_all(&request).map_err(io_error)?;
+        let mut response = String::new();
+        if self.reader.read_line(&mut response).map_err(io_error)? == 0 {
+            return err!("beanstalkd closed the connection");
+        }
+        Ok(response.split_whitespace().map(String::from).collect())
+    }
+    /// send a command whose response must start with [status]
+    fn expect(&mut self, line: &str, status: &str) -> QResult<Vec<String>> {
+        let response = self.command(line, None)?;
+        if response.first().map(String::as_str) != Some(status) {
+            return err!("beanstalkd answered {:?} to [{}]", response, line);
+        }
+        Ok(response)
+    }
+    /// read the body of [bytes] bytes following a response line
+    fn body(&mut self, bytes: &str) -> QResult<String> {
+        let Ok(bytes) = bytes.parse::<usize>() else {
+            return err!("invalid beanstalkd body size [{}]", bytes);
+        };
+        let mut body = vec![0; bytes + 2];
+        self.reader.read_exact(&mut body).map_err(io_error)?;
+        body.truncate(bytes);
+        match String::from_utf8(body) {
+            Ok(body) => Ok(body),
+            Err(_) => err!(ErrorKind::InvalidPayload, "beanstalkd job is not utf-8"),
+        }
+    }
+    /// the stats of a job, `None` if it does not exist
+    fn stats_job(&mut self, id: &JobId) -> QResult<Option<String>> {
+        let response = self.command(&format!("stats-job {}", id), None)?;
+        match response.as_slice() {
+            [status, bytes] if status == "OK" => Ok(Some(self.body(bytes)?)),
+            [status] if status == "NOT_FOUND" => Ok(None),
+            _ => err!("beanstalkd answered {:?} to stats-job", response),
+        }
+    }
+}
+
+/// read a field of the yaml stats of a job
+fn stats_field<'a>(stats: &'a str, name: &str) -> Option<&'a str> {
+    stats.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == name).then(|| value.trim())
+    })
+}
+
+impl BeanstalkQueue {
+    /// a queue on [tube] of the beanstalkd at [addr] such as `127.0.0.1:11300`
+    pub fn new(addr: impl Into<String>, tube: impl Into<String>, ttr: u32) -> Self {
+        BeanstalkQueue {
+            addr: addr.into(),
+            tube: tube.into(),
+            ttr,
+            delay: 0,
+            priority: 1024,
+            conn: Mutex::new(None),
+            reservations: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Set the seconds a pushed job waits before it can be reserved
+    pub fn delay(&mut self, delay: u32) -> &mut Self {
+        self.delay = delay;
+        self
+    }
+    /// Set the beanstalkd priority of pushed jobs, lower runs first
+    pub fn priority(&mut self, priority: u32) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+    /// put a reserved job back for [delay] seconds, false if [token] no longer holds it
+    pub fn release(&self, id: &JobId, token: &str, delay: u32) -> QResult<bool> {
+        let Some((_, mut conn)) = self.reservations.lock().unwrap().remove(token) else {
+            return Ok(false);
+        };
+        let response =
+            conn.command(&format!("release {} {} {}", id, self.priority, delay), None)?;
+        Ok(response.first().map(String::as_str) == Some("RELEASED"))
+    }
+    /// run [f] on the shared connection, it is dropped on an error so the next call
+    /// reconnects instead of reading the rest of a broken exchange
+    fn with_conn<T>(&self, f: impl FnOnce(&mut Connection) -> QResult<T>) -> QResult<T> {
+        let mut shared = self.conn.lock().unwrap();
+        let conn = match &mut *shared {
+            Some(conn) => conn,
+            None => shared.insert(Connection::open(&self.addr, &self.tube)?),
+        };
+        let result = f(conn);
+        if result.is_err() {
+            *shared = None;
+        }
+        result
+    }
+}
+
+impl QueueBackend for BeanstalkQueue {
+    fn push_message(&self, message: String) -> QResult<JobId> {
+        let line = format!(
+            "put {} {} {} {}",
+            self.priority,
+            self.delay,
+            self.ttr.max(1),
+            message.len()
+        );
+        self.with_conn(|conn| {
+            let response = conn.command(&line, Some(message.as_bytes()))?;
+            match response.as_slice() {
+                [status, id] if status == "INSERTED" => id.parse(),
+                _ => err!("beanstalkd answered {:?} to put", response),
+            }
+        })
+    }
+    fn reserve(&self, timeout: u64) -> QResult<ReservedJob> {
+        let mut conn = Connection::open(&self.addr, &self.tube)?;
+        let response = conn.command(&format!("reserve-with-timeout {}", timeout), None)?;
+        let (id, message) = match response.as_slice() {
+            [status, id, bytes] if status == "RESERVED" => {
+                (id.parse::<JobId>()?, conn.body(bytes)?)
+            }
+            [status] if status == "TIMED_OUT" => return err!(ErrorKind::NotFound, "No job found"),
+            _ => return err!("beanstalkd answered {:?} to reserve", response),
+        };
+        let stats = conn.stats_job(&id)?.unwrap_or_default();
+        let attempts = stats_field(&stats, "reserves")
+            .and_then(|reserves| reserves.parse().ok())
+            .unwrap_or(1);
+        let ttr = stats_field(&stats, "ttr")
+            .and_then(|ttr| ttr.parse().ok())
+            .unwrap_or(self.ttr);
+        let token = ulid::Ulid::new().to_string();
+        self.reservations
+            .lock()
+            .unwrap()
+            .insert(token.clone(), (timestamp()? + ttr as u64, conn));
+        Ok(ReservedJob {
+            id,
+            message,
+            ttr,
+            attempts,
+            token,
+            metadata: BTreeMap::new(),
+        })
+    }
+    fn delete(&self, id: &JobId, token: &str) -> QResult<bool> {
+        let Some((_, mut conn)) = self.reservations.lock().unwrap().remove(token) else {
+            return Ok(false);
+        };
+        let response = conn.command(&format!("delete {}", id), None)?;
+        Ok(response.first().map(String::as_str) == Some("DELETED"))
+    }
+    fn move_expired(&self) -> QResult<()> {
+        let now = timestamp()?;
+        self.reservations
+            .lock()
+            .unwrap()
+            .retain(|_, (expire, _)| *expire > now);
+        Ok(())
+    }
+    fn status(&self, id: &JobId) -> QResult<u8> {
+        let Some(stats) = self.with_conn(|conn| conn.stats_job(id))? else {
+            return Ok(STATUS_DONE);
+        };
+        let status = match stats_field(&stats, "state") {
+            Some("reserved") => STATUS_RESERVED,
+            Some("buried") => STATUS_BURIED,
+            _ => STATUS_WAITING,
+        };
+        Ok(status)
+    }
+    fn clear(&self) -> QResult<()> {
+        // closing the connections releases their reservations
+        self.reservations.lock().unwrap().clear();
+        self.with_conn(|conn| {
+            for peek in ["peek-ready", "peek-delayed", "peek-buried"] {
+                loop {
+                    let response = conn.command(peek, None)?;
+                    let id = match response.as_slice() {
+                        [status, id, bytes] if status == "FOUND" => {
+                            conn.body(bytes)?;
+                            id.clone()
+                        }
+                        [status] if status == "NOT_FOUND" => break,
+                        _ => return err!("beanstalkd answered {:?} to {}", response, peek),
+                    };
+                    conn.command(&format!("delete {}", id), None)?;
+                }
+            }
+            Ok(())
+        })
+    }
+    fn name(&self) -> &str {
+        &self.tube
+    }
+}
+
+// test beanstalk
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test read the fields of job stats
+    #[test]
+    fn test_stats_field() {
+        let stats = "---\nid: 42\ntube: emails\nstate: reserved\npri: 1024\nttr: 60\nreserves: 3\n";
+        assert_eq!(stats_field(stats, "state"), Some("reserved"));
+        assert_eq!(stats_field(stats, "reserves"), Some("3"));
+        assert_eq!(stats_field(stats, "ttr"), Some("60"));
+        assert_eq!(stats_field(stats, "kicks"), None);
+    }
+    // test an unreachable beanstalkd is an error
+    #[test]
+    fn test_unreachable() {
+        let queue = BeanstalkQueue::new("127.0.0.1:1", "emails", 60);
+        assert!(queue.push_message("{}".to_string()).is_err());
+        assert!(queue.reserve(0).is_err());
+    }
+}
@@ -0,0 +1,431 @@
+//! command line tools to operate queue-rs channels
+//!
+//! ```text
+//! queue-rs drain --channel <name> [--grace 60s] [--redis redis://127.0.0.1/]
+//! queue-rs stats (--channel <name> | --all [--prefix <prefix>]) [--redis redis://127.0.0.1/]
+//! queue-rs duplicates --channel <name> [--redis redis://127.0.0.1/]
+//! queue-rs retry --channel <name> [--type <job type>] [--tag <tag>] [--delay 0s] [--rate <jobs per second>] [--max-attempts <n>] [--redis redis://127.0.0.1/]
+//! queue-rs tail --channel <name> [--redis redis://127.0.0.1/]
+//! queue-rs config --channel <name> [--ttr 60s] [--delay 0s] [--attempts <n>] [--execution-timeout 30s] [--retry-backoff 10s] [--retry-backoff-max 1h] [--rate-limit <bucket>=<limit>/<period>|off] [--concurrency <n>] [--redis redis://127.0.0.1/]
+//! ```
+//! the redis url defaults to the `QUEUE_RS_REDIS` environment variable
+use queue_rs::config::ChannelConfig;
+use queue_rs::error::ErrorKind;
+use queue_rs::queue::{JobFilter, LifecycleEvent, Queue, RetryOptions};
+use queue_rs::{err, QResult};
+use std::collections::{BTreeMap, HashMap};
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "usage: queue-rs drain --channel <name> [--grace 60s] [--redis <url>]
+       queue-rs stats (--channel <name> | --all [--prefix <prefix>]) [--redis <url>]
+       queue-rs duplicates --channel <name> [--redis <url>]
+       queue-rs retry --channel <name> [--type <job type>] [--tag <tag>] [--delay 0s] [--rate <n>] [--max-attempts <n>] [--redis <url>]
+       queue-rs tail --channel <name> [--redis <url>]
+       queue-rs config --channel <name> [--ttr 60s] [--delay 0s] [--attempts <n>] [--execution-timeout 30s] [--retry-backoff 10s] [--retry-backoff-max 1h] [--rate-limit <bucket>=<limit>/<period>|off] [--concurrency <n>] [--redis <url>]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, options)) = args.split_first() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    let options = match parse_options(options) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let result = match command.as_str() {
+        "drain" => drain(&options),
+        "stats" => stats(&options),
+        "retry" => retry(&options),
+        "duplicates" => duplicates(&options),
+        "tail" => tail(&options),
+        "config" => config(&options),
+        _ => err!(ErrorKind::Other, "unknown command [{}]", command),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// pause the channel and wait for its reserved jobs, exit with 1 if some are still in flight
+fn drain(options: &HashMap<String, String>) -> QResult<ExitCode> {
+    let queue = queue(options)?;
+    let grace = match options.get("grace") {
+        Some(grace) => parse_duration(grace)?,
+        None => Duration::from_secs(60),
+    };
+    let in_flight = queue.drain(grace)?;
+    if in_flight.is_empty() {
+        println!("drained");
+        return Ok(ExitCode::SUCCESS);
+    }
+    println!("{} jobs still in flight:", in_flight.len());
+    for id in in_flight {
+        println!("{}", id);
+    }
+    Ok(ExitCode::FAILURE)
+}
+
+/// print the depth of one channel or of every channel found with `--all`
+fn stats(options: &HashMap<String, String>) -> QResult<ExitCode> {
+    let redis = client(options)?;
+    let overviews = if options.contains_key("all") {
+        let prefix = options.get("prefix").map(String::as_str).unwrap_or("");
+        queue_rs::discover(&redis, prefix)?
+    } else {
+        match options.get("channel") {
+            Some(channel) => vec![Queue::new(channel.as_str(), redis).overview()?],
+            None => return err!(ErrorKind::Other, "missing --channel or --all"),
+        }
+    };
+    let width = overviews
+        .iter()
+        .map(|overview| overview.channel.len())
+        .max()
+        .unwrap_or(0)
+        .max(7);
+    println!(
+        "{:<width$} {:>9} {:>9} {:>9} {:>9} {:>10}",
+        "channel", "waiting", "delayed", "reserved", "failed", "oldest"
+    );
+    for overview in overviews {
+        let oldest = overview
+            .oldest_age
+            .map(|age| format!("{}s", age))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<width$} {:>9} {:>9} {:>9} {:>9} {:>10}",
+            overview.channel,
+            overview.waiting,
+            overview.delayed,
+            overview.reserved,
+            overview.buried,
+            oldest
+        );
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// kick the failed jobs back, delayed by `--delay` and ramped to `--rate` jobs per second,
+/// with `--max-attempts` attempts each in place of the channel attempts
+fn retry(options: &HashMap<String, String>) -> QResult<ExitCode> {
+    let queue = queue(options)?;
+    let filter = JobFilter {
+        job_type: options.get("type").cloned(),
+        tag: options.get("tag").cloned(),
+        ..Default::default()
+    };
+    let delay = match options.get("delay") {
+        Some(delay) => parse_duration(delay)?.as_secs() as u32,
+        None => 0,
+    };
+    let per_second = match options.get("rate").map(|rate| rate.parse::<u32>()) {
+        Some(Ok(rate)) => rate,
+        Some(Err(_)) => return err!(ErrorKind::Other, "invalid --rate"),
+        None => 0,
+    };
+    let max_attempts = match options
+        .get("max-attempts")
+        .map(|attempts| attempts.parse::<u32>())
+    {
+        Some(Ok(attempts)) => attempts,
+        Some(Err(_)) => return err!(ErrorKind::Other, "invalid --max-attempts"),
+        None => 0,
+    };
+    let options = RetryOptions {
+        delay,
+        per_second,
+        max_attempts,
+    };
+    let retried = queue.retry_where_with(&filter, &options)?;
+    println!("{} jobs retried", retried.len());
+    Ok(ExitCode::SUCCESS)
+}
+
+/// print the duplicate waiting and delayed jobs per type and the largest groups
+fn duplicates(options: &HashMap<String, String>) -> QResult<ExitCode> {
+    let groups = queue(options)?.duplicates()?;
+    if groups.is_empty() {
+        println!("no duplicates");
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut per_type: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for group in &groups {
+        let counts = per_type
+            .entry(group.job_type.as_deref().unwrap_or("unknown"))
+            .or_default();
+        counts.0 += 1;
+        counts.1 += group.ids.len() - 1;
+    }
+    println!("{:<30} {:>9} {:>10}", "type", "groups", "redundant");
+    for (job_type, (groups, redundant)) in per_type {
+        println!("{:<30} {:>9} {:>10}", job_type, groups, redundant);
+    }
+    println!();
+    for group in groups.iter().take(10) {
+        let ids: Vec<String> = group.ids.iter().take(5).map(|id| id.to_string()).collect();
+        println!(
+            "{} x{}: {}",
+            group.job_type.as_deref().unwrap_or("unknown"),
+            group.ids.len(),
+            ids.join(" ")
+        );
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// print the lifecycle events of the channel as they come, until interrupted
+fn tail(options: &HashMap<String, String>) -> QResult<ExitCode> {
+    let queue = queue(options)?;
+    eprintln!(
+        "following [{}], events are recorded by queues with an event stream",
+        queue.name()
+    );
+    let mut last: Option<String> = None;
+    loop {
+        for event in queue.events_after(last.as_deref(), Duration::from_secs(5))? {
+            println!("{}", format_event(&event));
+            last = Some(event.id);
+        }
+    }
+}
+
+/// one line of `tail`, the time is the UTC time of the stream entry
+fn format_event(event: &LifecycleEvent) -> String {
+    let millis: u64 = event
+        .id
+        .split('-')
+        .next()
+        .and_then(|ms| ms.parse().ok())
+        .unwrap_or(0);
+    let seconds = millis / 1000 % 86400;
+    let mut line = format!(
+        "{:02}:{:02}:{:02}.{:03} {:<9} {:<12} {}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        millis % 1000,
+        event.event,
+        event.job_id.as_str(),
+        event.job_type.as_deref().unwrap_or("-")
+    );
+    if let Some(ms) = event.duration_ms {
+        line.push_str(&format!(" {}ms", ms));
+    }
+    if let Some(error) = &event.error {
+        line.push_str(&format!(" {}", error));
+    }
+    line
+}
+
+/// store the channel defaults given as options and print the stored ones, workers apply
+/// them when they reload the config
+fn config(options: &HashMap<String, String>) -> QResult<ExitCode> {
+    let queue = queue(options)?;
+    let mut config = queue.config()?;
+    if set_config(&mut config, options)? {
+        queue.save_config(&config)?;
+    }
+    let seconds = |value: Option<u32>| value.map(|v| format!("{}s", v));
+    for (name, value) in [
+        ("ttr", seconds(config.ttr)),
+        ("delay", seconds(config.delay)),
+        ("attempts", config.attempts.map(|v| v.to_string())),
+        ("execution-timeout", seconds(config.execution_timeout)),
+        ("retry-backoff", seconds(config.retry_backoff)),
+        ("retry-backoff-max", seconds(config.retry_backoff_max)),
+        ("concurrency", config.concurrency.map(|v| v.to_string())),
+        ("paused", config.paused.map(|v| v.to_string())),
+    ] {
+        println!("{:<18} {}", name, value.as_deref().unwrap_or("-"));
+    }
+    for (bucket, rate) in &config.rate_limits {
+        println!("{:<18} {}={}", "rate-limit", bucket, rate);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// set the config values given as options, return whether any was given
+fn set_config(config: &mut ChannelConfig, options: &HashMap<String, String>) -> QResult<bool> {
+    let mut changed = false;
+    for (option, slot) in [
+        ("ttr", &mut config.ttr),
+        ("delay", &mut config.delay),
+        ("execution-timeout", &mut config.execution_timeout),
+        ("retry-backoff", &mut config.retry_backoff),
+        ("retry-backoff-max", &mut config.retry_backoff_max),
+    ] {
+        if let Some(value) = options.get(option) {
+            *slot = Some(parse_duration(value)?.as_secs() as u32);
+            changed = true;
+        }
+    }
+    for (option, slot) in [
+        ("attempts", &mut config.attempts),
+        ("concurrency", &mut config.concurrency),
+    ] {
+        if let Some(value) = options.get(option) {
+            let Ok(value) = value.parse() else {
+                return err!(ErrorKind::Other, "invalid --{}", option);
+            };
+            *slot = Some(value);
+            changed = true;
+        }
+    }
+    if let Some(rate) = options.get("rate-limit") {
+        let Some((bucket, rate)) = rate.split_once('=') else {
+            return err!(
+                ErrorKind::Other,
+                "invalid --rate-limit, expected <bucket>=<limit>/<period>"
+            );
+        };
+        if rate == "off" {
+            config.rate_limits.remove(bucket);
+        } else {
+            config.rate_limits.insert(bucket.to_string(), rate.parse()?);
+        }
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// open the queue of the `--channel` option
+fn queue(options: &HashMap<String, String>) -> QResult<Queue> {
+    let Some(channel) = options.get("channel") else {
+        return err!(ErrorKind::Other, "missing --channel");
+    };
+    Ok(Queue::new(channel.as_str(), client(options)?))
+}
+
+/// connect to the `--redis` url
+fn client(options: &HashMap<String, String>) -> QResult<redis::Client> {
+    let url = options
+        .get("redis")
+        .cloned()
+        .or_else(|| std::env::var("QUEUE_RS_REDIS").ok())
+        .unwrap_or_else(|| "redis://127.0.0.1/".to_string());
+    Ok(redis::Client::open(url)?)
+}
+
+/// read `--name value` pairs, a name without value is a flag set to `true`
+fn parse_options(args: &[String]) -> QResult<HashMap<String, String>> {
+    let mut options = HashMap::new();
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        let Some(name) = arg.strip_prefix("--") else {
+            return err!(ErrorKind::Other, "unexpected argument [{}]", arg);
+        };
+        let value = match args.next_if(|value| !value.starts_with("--")) {
+            Some(value) => value.clone(),
+            None => "true".to_string(),
+        };
+        options.insert(name.to_string(), value);
+    }
+    Ok(options)
+}
+
+/// parse `500ms`, `60s`, `5m`, `1h` or plain seconds
+fn parse_duration(value: &str) -> QResult<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let Ok(number) = number.parse::<u64>() else {
+        return err!(ErrorKind::Other, "invalid duration [{}]", value);
+    };
+    let duration = match unit {
+        "ms" => Duration::from_millis(number),
+        "" | "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number * 60),
+        "h" => Duration::from_secs(number * 3600),
+        _ => return err!(ErrorKind::Other, "invalid duration [{}]", value),
+    };
+    Ok(duration)
+}
+
+// test cli
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test parse duration work
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("60s").unwrap(), Duration::from_secs(60));
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("5d").is_err());
+    }
+    // test a tail line shows the time, event, job and duration
+    #[test]
+    fn test_format_event() {
+        let event = LifecycleEvent {
+            id: "1700000123456-0".to_string(),
+            event: "failed".to_string(),
+            job_id: "42".parse().unwrap(),
+            job_type: Some("SendEmail".to_string()),
+            duration_ms: Some(120),
+            error: Some("smtp down".to_string()),
+        };
+        assert_eq!(
+            format_event(&event),
+            "22:15:23.456 failed    42           SendEmail 120ms smtp down"
+        );
+    }
+    // test config options set the channel defaults
+    #[test]
+    fn test_set_config() {
+        let options = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let mut config = ChannelConfig::default();
+        assert!(!set_config(&mut config, &options(&[("channel", "mail")])).unwrap());
+        let set = options(&[
+            ("retry-backoff", "10s"),
+            ("retry-backoff-max", "1h"),
+            ("attempts", "5"),
+            ("concurrency", "4"),
+            ("rate-limit", "smtp=10/60"),
+        ]);
+        assert!(set_config(&mut config, &set).unwrap());
+        assert_eq!(config.retry_backoff, Some(10));
+        assert_eq!(config.retry_backoff_max, Some(3600));
+        assert_eq!((config.attempts, config.concurrency), (Some(5), Some(4)));
+        assert_eq!(config.rate_limits["smtp"].to_string(), "10/60");
+        assert!(set_config(&mut config, &options(&[("rate-limit", "smtp=off")])).unwrap());
+        assert!(config.rate_limits.is_empty());
+        for (option, value) in [
+            ("rate-limit", "smtp"),
+            ("rate-limit", "smtp=10"),
+            ("attempts", "x"),
+        ] {
+            assert!(set_config(&mut config, &options(&[(option, value)])).is_err());
+        }
+    }
+    // test parse options work
+    #[test]
+    fn test_parse_options() {
+        let args: Vec<String> = ["--channel", "mail", "--all", "--grace", "10s"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let options = parse_options(&args).unwrap();
+        assert_eq!(options.get("channel").map(String::as_str), Some("mail"));
+        assert_eq!(options.get("all").map(String::as_str), Some("true"));
+        assert_eq!(options.get("grace").map(String::as_str), Some("10s"));
+        assert!(parse_options(&args[1..]).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use crate::QResult;
+use std::fmt::Debug;
+
+/// A store for messages too large to live in redis, see `Queue::blob_store`
+pub trait BlobStore: Send + Sync + Debug {
+    /// store [data] under [key], replacing any previous value
+    fn put(&self, key: &str, data: &[u8]) -> QResult<()>;
+    /// read the data stored under [key], `None` if there is none
+    fn get(&self, key: &str) -> QResult<Option<Vec<u8>>>;
+    /// delete the data stored under [key], deleting a missing key is not an error
+    fn delete(&self, key: &str) -> QResult<()>;
+}
+
+#[cfg(feature = "s3")]
+pub use s3::S3BlobStore;
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use super::BlobStore;
+    use crate::error::{ErrorKind, QError};
+    use crate::QResult;
+    use object_store::aws::{AmazonS3, AmazonS3Builder};
+    use object_store::path::Path;
+    use object_store::{ObjectStore, PutPayload};
+    use tokio::runtime::Runtime;
+
+    impl From<object_store::Error> for QError {
+        fn from(err: object_store::Error) -> Self {
+            QError::new(ErrorKind::Other, err.to_string())
+        }
+    }
+
+    /// Store blobs in an S3 bucket, the blocking calls run on a private runtime
+    #[derive(Debug)]
+    pub struct S3BlobStore {
+        store: AmazonS3,
+        runtime: Runtime,
+    }
+
+    impl S3BlobStore {
+        /// connect to [bucket] with the credentials and region of the `AWS_*` environment variables
+        pub fn new(bucket: &str) -> QResult<Self> {
+            let store = AmazonS3Builder::from_env()
+                .with_bucket_name(bucket)
+                .build()?;
+            Self::with_store(store)
+        }
+        /// use an already configured S3 client
+        pub fn with_store(store: AmazonS3) -> QResult<Self> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| QError::new(ErrorKind::Other, e.to_string()))?;
+            Ok(S3BlobStore { store, runtime })
+        }
+    }
+
+    impl BlobStore for S3BlobStore {
+        fn put(&self, key: &str, data: &[u8]) -> QResult<()> {
+            let payload = PutPayload::from(data.to_vec());
+            self.runtime
+                .block_on(self.store.put(&Path::from(key), payload))?;
+            Ok(())
+        }
+        fn get(&self, key: &str) -> QResult<Option<Vec<u8>>> {
+            self.runtime.block_on(async {
+                match self.store.get(&Path::from(key)).await {
+                    Ok(result) => Ok(Some(result.bytes().await?.to_vec())),
+                    Err(object_store::Error::NotFound { .. }) => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            })
+        }
+        fn delete(&self, key: &str) -> QResult<()> {
+            match self.runtime.block_on(self.store.delete(&Path::from(key))) {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+}
+
+// test blob store
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct MemoryBlobStore(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl BlobStore for MemoryBlobStore {
+        fn put(&self, key: &str, data: &[u8]) -> QResult<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), data.to_vec());
+            Ok(())
+        }
+        fn get(&self, key: &str) -> QResult<Option<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+        fn delete(&self, key: &str) -> QResult<()> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    // test blob store usable as a trait object
+    #[test]
+    fn test_blob_store() {
+        let store: Box<dyn BlobStore> = Box::new(MemoryBlobStore::default());
+        store.put("test/1", b"payload").unwrap();
+        assert_eq!(
+            store.get("test/1").unwrap().as_deref(),
+            Some(&b"payload"[..])
+        );
+        store.delete("test/1").unwrap();
+        store.delete("test/1").unwrap();
+        assert!(store.get("test/1").unwrap().is_none());
+    }
+}
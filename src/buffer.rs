@@ -0,0 +1,177 @@
+use crate::error::{ErrorKind, QError};
+use crate::id::JobId;
+use crate::{err, QResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+/// A push kept by the producer while redis was unreachable
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BufferedJob {
+    /// the id returned by `push`, buffered jobs get a ulid whatever the id scheme
+    pub id: JobId,
+    /// the unix time the job becomes due, 0 when it was pushed without delay
+    pub due: u64,
+    pub message: String,
+    /// the ambient values of the pushing thread
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ambient: BTreeMap<String, String>,
+}
+
+/// Keeps pushes on the producer side while redis is down, see `Queue::push_buffer`.
+/// A memory buffer is lost when the process exits, a file buffer survives restarts and
+/// is written before `push` returns. Jobs flushed right before a crash may be pushed
+/// twice, with the same id
+#[derive(Debug)]
+pub struct PushBuffer {
+    /// the most jobs kept, pushes beyond fail with the redis error
+    capacity: usize,
+    file: Option<PathBuf>,
+    /// whether each buffered push is synced to disk before `push` returns
+    sync: bool,
+    jobs: Mutex<VecDeque<BufferedJob>>,
+}
+
+impl PushBuffer {
+    /// keep up to [capacity] jobs in memory
+    pub fn memory(capacity: usize) -> Self {
+        PushBuffer {
+            capacity,
+            file: None,
+            sync: false,
+            jobs: Mutex::new(VecDeque::new()),
+        }
+    }
+    /// keep up to [capacity] jobs in the file at [path], the jobs left there by a
+    /// previous process are loaded and flushed with the next push
+    pub fn file(path: impl Into<PathBuf>, capacity: usize) -> QResult<Self> {
+        let path = path.into();
+        let mut jobs = VecDeque::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.map_err(io_error)?;
+                    if !line.is_empty() {
+                        jobs.push_back(serde_json::from_str(&line)?);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(io_error(e)),
+        }
+        Ok(PushBuffer {
+            capacity,
+            file: Some(path),
+            sync: true,
+            jobs: Mutex::new(jobs),
+        })
+    }
+    /// Set whether each buffered push is synced to disk, on by default for a file buffer.
+    /// Without it a push returns faster but the OS may lose it on a power failure
+    pub fn sync(&mut self, sync: bool) -> &mut Self {
+        self.sync = sync;
+        self
+    }
+    /// the number of buffered jobs
+    pub fn len(&self) -> usize {
+        self.jobs.lock().unwrap().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// keep a job, an error if the buffer is full
+    pub(crate) fn add(&self, job: BufferedJob) -> QResult<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() >= self.capacity {
+            return err!(
+                ErrorKind::Other,
+                "push buffer is full with {} jobs",
+                jobs.len()
+            );
+        }
+        if let Some(path) = &self.file {
+            let mut line = serde_json::to_string(&job)?;
+            line.push('\n');
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(io_error)?;
+            file.write_all(line.as_bytes()).map_err(io_error)?;
+            if self.sync {
+                file.sync_data().map_err(io_error)?;
+            }
+        }
+        jobs.push_back(job);
+        Ok(())
+    }
+    /// lock the buffered jobs to flush them, call `flushed` once they are pushed
+    pub(crate) fn lock(&self) -> MutexGuard<'_, VecDeque<BufferedJob>> {
+        self.jobs.lock().unwrap()
+    }
+    /// write the jobs left after a flush back to the file
+    pub(crate) fn flushed(&self, jobs: &VecDeque<BufferedJob>) -> QResult<()> {
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+        if jobs.is_empty() {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(io_error(e)),
+                _ => Ok(()),
+            };
+        }
+        let mut content = String::new();
+        for job in jobs {
+            content.push_str(&serde_json::to_string(job)?);
+            content.push('\n');
+        }
+        // replace the file at once, a crash keeps either the old or the new jobs
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).map_err(io_error)?;
+        fs::rename(&tmp, path).map_err(io_error)
+    }
+}
+
+fn io_error(e: std::io::Error) -> QError {
+    QError::new(ErrorKind::Other, e.to_string())
+}
+
+// test push buffer
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: u64) -> BufferedJob {
+        BufferedJob {
+            id: JobId::from(id),
+            due: 0,
+            message: "{\"type\":\"TestJob\",\"text\":\"a\\nb\"}".to_string(),
+            ambient: BTreeMap::new(),
+        }
+    }
+    // test a file buffer is bounded and reloaded
+    #[test]
+    fn test_file_buffer() {
+        let path = std::env::temp_dir().join(format!("queue-rs-buffer-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let buffer = PushBuffer::file(&path, 2).unwrap();
+        buffer.add(job(1)).unwrap();
+        buffer.add(job(2)).unwrap();
+        assert!(buffer.add(job(3)).is_err());
+
+        let reloaded = PushBuffer::file(&path, 2).unwrap();
+        assert_eq!(reloaded.len(), 2);
+        let mut jobs = reloaded.lock();
+        assert_eq!(jobs.pop_front(), Some(job(1)));
+        reloaded.flushed(&jobs).unwrap();
+        drop(jobs);
+        assert_eq!(PushBuffer::file(&path, 2).unwrap().len(), 1);
+        let mut jobs = reloaded.lock();
+        jobs.clear();
+        reloaded.flushed(&jobs).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,33 @@
+use crate::QResult;
+use std::fmt::Debug;
+
+/// How a json message is stored in redis, see `Queue::codec`. The name of the codec is
+/// recorded in the `codec` header of each message, so a channel can move between codecs
+/// while workers decode both
+pub trait Codec: Send + Sync + Debug {
+    /// the name recorded in the header, unique among the codecs of a channel
+    fn name(&self) -> &str;
+    /// turn the json message into the stored text, binary codecs need a text encoding
+    fn encode(&self, message: &str) -> QResult<String>;
+    /// turn the stored text back into the json message
+    fn decode(&self, stored: &str) -> QResult<String>;
+}
+
+/// The json message stored as is, messages without a `codec` header use it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+/// the name of `JsonCodec`
+pub const JSON: &str = "json";
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &str {
+        JSON
+    }
+    fn encode(&self, message: &str) -> QResult<String> {
+        Ok(message.to_string())
+    }
+    fn decode(&self, stored: &str) -> QResult<String> {
+        Ok(stored.to_string())
+    }
+}
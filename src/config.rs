@@ -0,0 +1,162 @@
+use crate::error::ErrorKind;
+use crate::queue::RateLimit;
+use crate::{err, QResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// the prefix of the fields holding a rate limit bucket, `rate_limit.<bucket>`
+pub(crate) const RATE_LIMIT_FIELD: &str = "rate_limit.";
+
+/// The defaults of a channel stored in the `<channel>.config` hash,
+/// read by producers and workers so they can be tuned without a redeploy
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelConfig {
+    /// The seconds to live of the job
+    pub ttr: Option<u32>,
+    /// The delay of the job
+    pub delay: Option<u32>,
+    /// The number of attempts
+    pub attempts: Option<u32>,
+    /// The seconds a job may execute before it is abandoned
+    pub execution_timeout: Option<u32>,
+    /// Whether workers stop reserving jobs from the channel
+    pub paused: Option<bool>,
+    /// The seconds before the first retry of a failed job, doubled on each next retry
+    pub retry_backoff: Option<u32>,
+    /// The most seconds between two retries
+    pub retry_backoff_max: Option<u32>,
+    /// The rate limit buckets by name, stored as `<limit>/<period>`
+    #[serde(default)]
+    pub rate_limits: BTreeMap<String, RateLimit>,
+    /// The number of jobs an async worker executes at the same time
+    pub concurrency: Option<u32>,
+}
+
+impl ChannelConfig {
+    /// parse the config from the fields of the redis hash, unknown fields are ignored
+    pub(crate) fn from_fields(fields: HashMap<String, String>) -> QResult<Self> {
+        let mut config = ChannelConfig::default();
+        for (field, value) in fields {
+            if field == "paused" {
+                config.paused = Some(value == "1" || value == "true");
+                continue;
+            }
+            if let Some(bucket) = field.strip_prefix(RATE_LIMIT_FIELD) {
+                let Ok(rate) = value.parse() else {
+                    return err!(
+                        ErrorKind::InvalidPayload,
+                        "invalid channel config {}:[{}]",
+                        field,
+                        value
+                    );
+                };
+                config.rate_limits.insert(bucket.to_string(), rate);
+                continue;
+            }
+            let slot = match field.as_str() {
+                "ttr" => &mut config.ttr,
+                "delay" => &mut config.delay,
+                "attempts" => &mut config.attempts,
+                "execution_timeout" => &mut config.execution_timeout,
+                "retry_backoff" => &mut config.retry_backoff,
+                "retry_backoff_max" => &mut config.retry_backoff_max,
+                "concurrency" => &mut config.concurrency,
+                _ => continue,
+            };
+            match value.parse::<u32>() {
+                Ok(value) => *slot = Some(value),
+                Err(_) => {
+                    return err!(
+                        ErrorKind::InvalidPayload,
+                        "invalid channel config {}:[{}]",
+                        field,
+                        value
+                    )
+                }
+            }
+        }
+        Ok(config)
+    }
+    /// the fields to store, `None` values are returned as fields to delete, the buckets
+    /// left out of `rate_limits` are not
+    pub(crate) fn to_fields(&self) -> (Vec<(String, String)>, Vec<String>) {
+        let mut set = Vec::new();
+        let mut unset = Vec::new();
+        let number = |value: Option<u32>| value.map(|v| v.to_string());
+        for (field, value) in [
+            ("ttr", number(self.ttr)),
+            ("delay", number(self.delay)),
+            ("attempts", number(self.attempts)),
+            ("execution_timeout", number(self.execution_timeout)),
+            ("paused", self.paused.map(|p| (p as u8).to_string())),
+            ("retry_backoff", number(self.retry_backoff)),
+            ("retry_backoff_max", number(self.retry_backoff_max)),
+            ("concurrency", number(self.concurrency)),
+        ] {
+            match value {
+                Some(value) => set.push((field.to_string(), value)),
+                None => unset.push(field.to_string()),
+            }
+        }
+        for (bucket, rate) in &self.rate_limits {
+            set.push((format!("{}{}", RATE_LIMIT_FIELD, bucket), rate.to_string()));
+        }
+        (set, unset)
+    }
+}
+
+// test channel config
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test config fields round trip
+    #[test]
+    fn test_config_fields() {
+        let config = ChannelConfig {
+            ttr: Some(60),
+            attempts: Some(3),
+            paused: Some(true),
+            retry_backoff: Some(5),
+            rate_limits: BTreeMap::from([(
+                "smtp".to_string(),
+                RateLimit {
+                    limit: 10,
+                    period: 60,
+                },
+            )]),
+            ..Default::default()
+        };
+        let (set, unset) = config.to_fields();
+        let field = |k: &str, v: &str| (k.to_string(), v.to_string());
+        assert_eq!(
+            set,
+            vec![
+                field("ttr", "60"),
+                field("attempts", "3"),
+                field("paused", "1"),
+                field("retry_backoff", "5"),
+                field("rate_limit.smtp", "10/60"),
+            ]
+        );
+        assert_eq!(
+            unset,
+            vec![
+                "delay",
+                "execution_timeout",
+                "retry_backoff_max",
+                "concurrency"
+            ]
+        );
+        let fields = set.into_iter().chain([field("unknown", "x")]).collect();
+        assert_eq!(ChannelConfig::from_fields(fields).unwrap(), config);
+        for (k, v) in [
+            ("ttr", "abc"),
+            ("rate_limit.smtp", "10"),
+            ("rate_limit.smtp", "a/1"),
+        ] {
+            let fields = HashMap::from([field(k, v)]);
+            assert!(ChannelConfig::from_fields(fields).is_err());
+        }
+    }
+}
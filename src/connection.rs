@@ -0,0 +1,154 @@
+#[cfg(all(feature = "cluster", any(feature = "async", test)))]
+use crate::err;
+#[cfg(all(feature = "cluster", any(feature = "async", test)))]
+use crate::error::ErrorKind;
+use crate::sentinel::Sentinel;
+use crate::QResult;
+use redis::{Cmd, ConnectionLike, RedisResult, Value};
+use std::fmt;
+use std::sync::Arc;
+
+/// A connection the commands of a queue run on, to one redis server or, with the
+/// `cluster` feature, to a redis cluster
+pub enum Connection {
+    Server(redis::Connection),
+    #[cfg(feature = "cluster")]
+    Cluster(Box<redis::cluster::ClusterConnection>),
+}
+
+impl Connection {
+    /// the keys matching [pattern], a cluster does not route `SCAN` so every primary is
+    /// asked with `KEYS` instead
+    pub(crate) fn keys_matching(&mut self, pattern: &str) -> QResult<Vec<String>> {
+        match self {
+            Connection::Server(conn) => {
+                let keys = redis::Commands::scan_match::<_, String>(conn, pattern)?;
+                Ok(keys.collect())
+            }
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(conn) => Ok(redis::cmd("KEYS").arg(pattern).query(conn)?),
+        }
+    }
+}
+
+impl From<redis::Connection> for Connection {
+    fn from(conn: redis::Connection) -> Self {
+        Connection::Server(conn)
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        match self {
+            Connection::Server(conn) => conn.req_packed_command(cmd),
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        match self {
+            Connection::Server(conn) => conn.req_packed_commands(cmd, offset, count),
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+    fn req_command(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        match self {
+            Connection::Server(conn) => conn.req_command(cmd),
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(conn) => conn.req_command(cmd),
+        }
+    }
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Server(conn) => conn.get_db(),
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(conn) => conn.get_db(),
+        }
+    }
+    fn supports_pipelining(&self) -> bool {
+        match self {
+            Connection::Server(conn) => conn.supports_pipelining(),
+            // a cluster connection sends a packed pipeline, a `MULTI` one included, to the
+            // node of the slot of its first command, which serves the pipelines of a queue
+            // as the keys of a hash tagged channel all share one slot
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(_) => true,
+        }
+    }
+    fn check_connection(&mut self) -> bool {
+        match self {
+            Connection::Server(conn) => conn.check_connection(),
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(conn) => conn.check_connection(),
+        }
+    }
+    fn is_open(&self) -> bool {
+        match self {
+            Connection::Server(conn) => conn.is_open(),
+            #[cfg(feature = "cluster")]
+            Connection::Cluster(conn) => conn.is_open(),
+        }
+    }
+}
+
+/// Where the connections of a queue go
+#[derive(Clone)]
+pub(crate) enum Connector {
+    Client(redis::Client),
+    /// the current master followed by the sentinels
+    Sentinel(Arc<Sentinel>),
+    #[cfg(feature = "cluster")]
+    Cluster(redis::cluster::ClusterClient),
+}
+
+impl Connector {
+    /// whether the connections go to a redis cluster
+    pub(crate) fn is_cluster(&self) -> bool {
+        #[cfg(feature = "cluster")]
+        if let Connector::Cluster(_) = self {
+            return true;
+        }
+        false
+    }
+    pub(crate) fn connect(&self) -> QResult<Connection> {
+        match self {
+            Connector::Client(client) => Ok(client.get_connection()?.into()),
+            Connector::Sentinel(sentinel) => Ok(sentinel.connection()?.into()),
+            #[cfg(feature = "cluster")]
+            Connector::Cluster(cluster) => {
+                Ok(Connection::Cluster(Box::new(cluster.get_connection()?)))
+            }
+        }
+    }
+    /// the client of the one server, the current master with sentinels, an error for
+    /// a cluster
+    #[cfg(any(feature = "async", test))]
+    pub(crate) fn client(&self) -> QResult<redis::Client> {
+        match self {
+            Connector::Client(client) => Ok(client.clone()),
+            Connector::Sentinel(sentinel) => sentinel.client(),
+            #[cfg(feature = "cluster")]
+            Connector::Cluster(_) => err!(
+                ErrorKind::Redis,
+                "a cluster queue has no single redis server, the operation needs one"
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Connector::Client(client) => f.debug_tuple("Client").field(client).finish(),
+            Connector::Sentinel(sentinel) => f.debug_tuple("Sentinel").field(sentinel).finish(),
+            #[cfg(feature = "cluster")]
+            Connector::Cluster(_) => f.write_str("Cluster"),
+        }
+    }
+}
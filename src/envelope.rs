@@ -0,0 +1,222 @@
+use crate::error::ErrorKind;
+use crate::{err, QResult};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// The stored form of a job in the messages hash: `ttr[|key=value]*;message`.
+/// Payloads written before metadata existed (`ttr;message`) decode unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Envelope {
+    /// The seconds to live of the job
+    pub ttr: u32,
+    /// The metadata stored along with the job
+    pub metadata: BTreeMap<String, String>,
+    /// The serialized job
+    pub message: String,
+}
+
+impl Envelope {
+    /// create an envelope without metadata
+    pub fn new(ttr: u32, message: String) -> Self {
+        Envelope {
+            ttr,
+            metadata: BTreeMap::new(),
+            message,
+        }
+    }
+    /// store the crc32 of the message, so `verify` can detect a corrupted payload
+    pub fn with_checksum(mut self) -> Self {
+        let crc = format!("{:08x}", crc32fast::hash(self.message.as_bytes()));
+        self.set("crc", crc);
+        self
+    }
+    /// check the message against its stored checksum, payloads without one pass
+    pub fn verify(&self) -> QResult<()> {
+        let Some(crc) = self.get("crc") else {
+            return Ok(());
+        };
+        let actual = format!("{:08x}", crc32fast::hash(self.message.as_bytes()));
+        if crc != actual {
+            return err!(
+                ErrorKind::InvalidPayload,
+                "checksum mismatch, stored:[{}] actual:[{}]",
+                crc,
+                actual
+            );
+        }
+        Ok(())
+    }
+    /// the type name of the job, kept in the metadata when the message is stored apart
+    pub fn job_type(&self) -> Option<String> {
+        match self.get("type") {
+            Some(name) => Some(name.to_string()),
+            None => job_type(&self.message),
+        }
+    }
+    /// get a metadata value
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+    /// set a metadata value
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+    /// encode the envelope as it is stored in redis
+    pub fn encode(&self) -> String {
+        let mut payload = String::with_capacity(self.message.len() + 16);
+        let _ = write!(payload, "{}", self.ttr);
+        for (key, value) in &self.metadata {
+            payload.push('|');
+            escape(&mut payload, key);
+            payload.push('=');
+            escape(&mut payload, value);
+        }
+        payload.push(';');
+        payload.push_str(&self.message);
+        payload
+    }
+    /// decode a stored payload, the message reuses the payload buffer
+    pub fn decode(mut payload: String) -> QResult<Self> {
+        let Some(pos) = payload.find(';') else {
+            return err!(ErrorKind::InvalidPayload, "missing ttr separator");
+        };
+        let mut header = payload[..pos].split('|');
+        let ttr = header.next().unwrap_or_default();
+        let ttr: u32 = match ttr.parse::<u32>() {
+            Ok(ttr) => ttr,
+            Err(_) => return err!(ErrorKind::InvalidPayload, "Invalid ttr:[{}]", ttr),
+        };
+        let mut metadata = BTreeMap::new();
+        for field in header {
+            let Some((key, value)) = field.split_once('=') else {
+                return err!(ErrorKind::InvalidPayload, "Invalid metadata:[{}]", field);
+            };
+            metadata.insert(unescape(key)?, unescape(value)?);
+        }
+        payload.drain(..=pos);
+        Ok(Envelope {
+            ttr,
+            metadata,
+            message: payload,
+        })
+    }
+}
+
+/// read the `type` field of a serialized job without deserializing it, typetag writes
+/// the tag first so this is usually a prefix read, other layouts fall back to a parse
+pub fn job_type(message: &str) -> Option<String> {
+    if let Some(rest) = message.strip_prefix("{\"type\":\"") {
+        if let Some(name) = rest.split('"').next() {
+            if !name.contains('\\') && rest.len() > name.len() {
+                return Some(name.to_string());
+            }
+        }
+    }
+    #[derive(Deserialize)]
+    struct JobType {
+        #[serde(rename = "type")]
+        name: String,
+    }
+    let job: JobType = serde_json::from_str(message).ok()?;
+    Some(job.name)
+}
+
+/// percent-encode the characters used as separators in the header
+fn escape(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '%' | ';' | '|' | '=' => {
+                let _ = write!(out, "%{:02X}", c as u8);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+fn unescape(value: &str) -> QResult<String> {
+    if !value.contains('%') {
+        return Ok(value.to_string());
+    }
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        let code = rest
+            .get(pos + 1..pos + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match code {
+            Some(code) => out.push(code as char),
+            None => return err!(ErrorKind::InvalidPayload, "Invalid escape in:[{}]", value),
+        }
+        rest = &rest[pos + 3..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// test envelope
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test payload parse keeps the message intact
+    #[test]
+    fn test_parse_payload() {
+        let envelope =
+            Envelope::decode("300;{\"type\":\"TestJob\",\"title\":\"a;b\"}".to_string()).unwrap();
+        assert_eq!(envelope.ttr, 300);
+        assert_eq!(envelope.message, "{\"type\":\"TestJob\",\"title\":\"a;b\"}");
+        assert!(envelope.metadata.is_empty());
+        assert!(Envelope::decode("abc;{}".to_string()).is_err());
+        assert!(Envelope::decode("{}".to_string()).is_err());
+    }
+    // test metadata round trip with separators escaped
+    #[test]
+    fn test_envelope_metadata() {
+        let mut envelope = Envelope::new(60, "{\"type\":\"TestJob\"}".to_string());
+        envelope.set("source", "web|1;a=b%");
+        let payload = envelope.encode();
+        assert_eq!(
+            payload,
+            "60|source=web%7C1%3Ba%3Db%25;{\"type\":\"TestJob\"}"
+        );
+        let decoded = Envelope::decode(payload).unwrap();
+        assert_eq!(decoded, envelope);
+        assert_eq!(decoded.get("source"), Some("web|1;a=b%"));
+        assert!(Envelope::decode("60|source;{}".to_string()).is_err());
+    }
+    // test read the job type work
+    #[test]
+    fn test_job_type() {
+        assert_eq!(
+            job_type("{\"type\":\"TestJob\",\"title\":\"a\"}").as_deref(),
+            Some("TestJob")
+        );
+        assert_eq!(
+            job_type("{\"title\":\"a\",\"type\":\"TestJob\"}").as_deref(),
+            Some("TestJob")
+        );
+        assert_eq!(
+            job_type("{\"type\":\"Test\\\"Job\"}").as_deref(),
+            Some("Test\"Job")
+        );
+        assert_eq!(job_type("{\"type\":\"TestJob"), None);
+        assert_eq!(job_type("{\"title\":\"a\"}"), None);
+        let mut envelope = Envelope::new(60, String::new());
+        envelope.set("type", "TestJob");
+        assert_eq!(envelope.job_type().as_deref(), Some("TestJob"));
+    }
+    // test checksum detects a modified message
+    #[test]
+    fn test_envelope_checksum() {
+        let envelope = Envelope::new(60, "{\"type\":\"TestJob\"}".to_string()).with_checksum();
+        let payload = envelope.encode();
+        assert!(Envelope::decode(payload.clone()).unwrap().verify().is_ok());
+        let corrupted = payload.replace("TestJob", "TestJod");
+        let err = Envelope::decode(corrupted).unwrap().verify().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidPayload);
+        assert!(Envelope::new(60, "{}".to_string()).verify().is_ok());
+    }
+}
@@ -1,43 +1,216 @@
+use serde::{Deserialize, Serialize};
+use std::backtrace::{Backtrace, BacktraceStatus};
 use std::fmt;
-#[derive(Debug)]
+
+/// The kind of a queue error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// error returned by redis
+    Redis,
+    /// error when converting a job from or to json
+    JsonConvert,
+    /// error when reading the system time
+    SystemTime,
+    /// a job id is invalid
+    InvalidJobId,
+    /// a stored payload can not be parsed
+    InvalidPayload,
+    /// no job found in the queue
+    NotFound,
+    /// an operation took too long
+    Timeout,
+    /// the rate limit bucket of a job is empty, the job was released to retry later
+    RateLimited,
+    /// the concurrency key of a job is held by another job, the job was released to retry later
+    Locked,
+    /// any other error
+    Other,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            ErrorKind::Redis => "Redis error",
+            ErrorKind::JsonConvert => "JsonConvert",
+            ErrorKind::SystemTime => "SystemTimeError",
+            ErrorKind::InvalidJobId => "InvalidJobId",
+            ErrorKind::InvalidPayload => "InvalidPayload",
+            ErrorKind::NotFound => "NotFound",
+            ErrorKind::Timeout => "Timeout",
+            ErrorKind::RateLimited => "RateLimited",
+            ErrorKind::Locked => "Locked",
+            ErrorKind::Other => "Other",
+        };
+        f.write_str(kind)
+    }
+}
+
+/// A queue error, serializable so it can be stored with failed jobs
+#[derive(Debug, Serialize, Deserialize)]
 pub struct QError {
-    kind: String,
+    kind: ErrorKind,
     message: String,
+    /// the backtrace captured when `RUST_BACKTRACE` is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backtrace: Option<String>,
+    /// the error this one was attached to as context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<Box<QError>>,
 }
 
 impl QError {
     /// init a error with kind and message
-    pub fn new(kind: impl Into<String>, message: String) -> Self {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        let backtrace = Backtrace::capture();
+        let backtrace = match backtrace.status() {
+            BacktraceStatus::Captured => Some(backtrace.to_string()),
+            _ => None,
+        };
+        QError {
+            kind,
+            message: message.into(),
+            backtrace,
+            source: None,
+        }
+    }
+    /// wrap the error with a context message, the kind is kept and the error becomes the source
+    pub fn context(self, context: impl Into<String>) -> Self {
         QError {
-            kind: kind.into(),
-            message,
+            kind: self.kind,
+            message: context.into(),
+            backtrace: None,
+            source: Some(Box::new(self)),
+        }
+    }
+    /// the kind of the error
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+    /// the message of the error
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    /// the backtrace captured where the error was created, if enabled
+    pub fn backtrace(&self) -> Option<&str> {
+        match &self.backtrace {
+            Some(backtrace) => Some(backtrace),
+            None => self.source.as_ref().and_then(|e| e.backtrace()),
         }
     }
 }
 
 impl fmt::Display for QError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "QueueError: {} {}", self.kind, self.message)
+        write!(f, "QueueError: {} {}", self.kind, self.message)?;
+        let mut source = self.source.as_deref();
+        while let Some(err) = source {
+            write!(f, ": {}", err.message)?;
+            source = err.source.as_deref();
+        }
+        Ok(())
+    }
+}
+
+/// attach context to errors of a result, like `.context("while reserving from channel X")`
+pub trait Context<T> {
+    /// wrap the error with a context message
+    fn context(self, context: impl Into<String>) -> Result<T, QError>;
+    /// wrap the error with a lazily built context message
+    fn with_context<C, F>(self, f: F) -> Result<T, QError>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C;
+}
+
+impl<T, E: Into<QError>> Context<T> for Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T, QError> {
+        self.map_err(|e| e.into().context(context))
+    }
+    fn with_context<C, F>(self, f: F) -> Result<T, QError>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C,
+    {
+        self.map_err(|e| e.into().context(f()))
     }
 }
 /// impl redis error
 impl From<redis::RedisError> for QError {
     fn from(err: redis::RedisError) -> Self {
-        QError::new("Redis error", err.to_string())
+        QError::new(ErrorKind::Redis, err.to_string())
     }
 }
 /// impl serde_json error
 impl From<serde_json::Error> for QError {
     fn from(err: serde_json::Error) -> Self {
-        QError::new("JsonConvert", err.to_string())
+        QError::new(ErrorKind::JsonConvert, err.to_string())
     }
 }
 
 /// impl SystemTimeError
 impl From<std::time::SystemTimeError> for QError {
     fn from(err: std::time::SystemTimeError) -> Self {
-        QError::new("SystemTimeError", err.to_string())
+        QError::new(ErrorKind::SystemTime, err.to_string())
+    }
+}
+
+impl std::error::Error for QError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
     }
 }
 
-impl std::error::Error for QError {}
+// test error
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{err, QResult};
+
+    // test err macro with kind and format args work
+    #[test]
+    fn test_err_macro() {
+        let e: QResult<()> = err!("No job found");
+        let e = e.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Other);
+        assert_eq!(e.message(), "No job found");
+
+        let n = 3;
+        let e: QResult<()> = err!(ErrorKind::Timeout, "failed after {}s", n);
+        let e = e.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Timeout);
+        assert_eq!(e.to_string(), "QueueError: Timeout failed after 3s");
+
+        let msg = String::from("dynamic message");
+        let e: QResult<()> = err!(msg);
+        assert_eq!(e.unwrap_err().message(), "dynamic message");
+    }
+    // test context keeps the kind and the source chain
+    #[test]
+    fn test_context() {
+        use std::error::Error;
+        let e: QResult<()> = err!(ErrorKind::Redis, "broken pipe");
+        let e = e
+            .context("while reserving from channel [test]")
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Redis);
+        assert_eq!(
+            e.to_string(),
+            "QueueError: Redis error while reserving from channel [test]: broken pipe"
+        );
+        assert_eq!(
+            e.source().unwrap().to_string(),
+            "QueueError: Redis error broken pipe"
+        );
+    }
+    // test error serialize keeps kind, message and source
+    #[test]
+    fn test_error_serde() {
+        let e = QError::new(ErrorKind::Redis, "broken pipe").context("while deleting job [1]");
+        let json = serde_json::to_string(&e).unwrap();
+        let de: QError = serde_json::from_str(&json).unwrap();
+        assert_eq!(de.kind(), ErrorKind::Redis);
+        assert_eq!(de.to_string(), e.to_string());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::connection::Connection;
+use crate::error::ErrorKind;
+use crate::{err, QError, QResult};
+use redis::{Commands, FromRedisValue, RedisResult, RedisWrite, ToRedisArgs, Value};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How a queue generates the ids of pushed jobs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdScheme {
+    /// per channel `message_id` counter, ids are only unique in one channel
+    #[default]
+    Counter,
+    /// time-sortable ULID, unique across channels and systems
+    Ulid,
+}
+
+/// Generates the ids of pushed jobs in place of the `IdScheme` of a queue, see
+/// `Queue::id_generator`
+pub trait IdGenerator: Send + Sync + fmt::Debug {
+    /// the id of the next pushed job, [conn] is the connection of the push for generators
+    /// keeping their state in redis
+    fn next_id(&self, conn: &mut Connection) -> QResult<JobId>;
+}
+
+/// Count ids with `INCR` on a redis key, which may be shared by several channels
+#[derive(Debug, Clone)]
+pub struct RedisCounter {
+    key: String,
+}
+
+impl RedisCounter {
+    /// count on [key], created on the first push
+    pub fn new(key: impl Into<String>) -> Self {
+        RedisCounter { key: key.into() }
+    }
+}
+
+impl IdGenerator for RedisCounter {
+    fn next_id(&self, conn: &mut Connection) -> QResult<JobId> {
+        let id: u64 = conn.incr(&self.key, 1)?;
+        Ok(JobId::from(id))
+    }
+}
+
+/// Generate ULIDs locally, without a round trip
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UlidGenerator;
+
+impl IdGenerator for UlidGenerator {
+    fn next_id(&self, _conn: &mut Connection) -> QResult<JobId> {
+        Ok(JobId::ulid())
+    }
+}
+
+/// Generate time-sortable numeric ids locally: 41 bits of milliseconds since [epoch],
+/// 10 bits of worker id and 12 bits of sequence, so producers with distinct worker ids
+/// never collide
+#[derive(Debug)]
+pub struct Snowflake {
+    worker_id: u64,
+    /// the unix time in milliseconds the timestamps count from
+    epoch: u64,
+    /// the milliseconds and sequence of the last id
+    last: Mutex<(u64, u64)>,
+}
+
+impl Snowflake {
+    /// the default epoch, 2020-01-01T00:00:00Z
+    pub const EPOCH: u64 = 1_577_836_800_000;
+    const WORKER_BITS: u64 = 10;
+    const SEQUENCE_BITS: u64 = 12;
+
+    /// [worker_id] must be below 1024 and unique among the producers of a channel
+    pub fn new(worker_id: u16) -> QResult<Self> {
+        Self::with_epoch(worker_id, Self::EPOCH)
+    }
+    /// use another epoch, in unix milliseconds
+    pub fn with_epoch(worker_id: u16, epoch: u64) -> QResult<Self> {
+        if worker_id as u64 >= 1 << Self::WORKER_BITS {
+            return err!("snowflake worker id [{}] is not below 1024", worker_id);
+        }
+        Ok(Snowflake {
+            worker_id: worker_id as u64,
+            epoch,
+            last: Mutex::new((0, 0)),
+        })
+    }
+    /// the id of [millis] and [sequence]
+    fn compose(&self, millis: u64, sequence: u64) -> u64 {
+        (millis.saturating_sub(self.epoch) << (Self::WORKER_BITS + Self::SEQUENCE_BITS))
+            | (self.worker_id << Self::SEQUENCE_BITS)
+            | sequence
+    }
+    /// the next id, waiting for the next millisecond when 4096 ids were taken in this one
+    fn generate(&self) -> QResult<u64> {
+        let mut last = self.last.lock().unwrap();
+        loop {
+            // a clock moving backwards keeps counting on the last millisecond
+            let now =
+                (SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64).max(last.0);
+            let sequence = if now == last.0 { last.1 + 1 } else { 0 };
+            if sequence < 1 << Self::SEQUENCE_BITS {
+                *last = (now, sequence);
+                return Ok(self.compose(now, sequence));
+            }
+            // the sequence of this millisecond is used up
+            std::thread::sleep(Duration::from_micros(100));
+        }
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn next_id(&self, _conn: &mut Connection) -> QResult<JobId> {
+        Ok(JobId::from(self.generate()?))
+    }
+}
+
+/// What a counter queue does when the next id is still used by a stored job,
+/// which happens when the `message_id` counter is flushed or evicted but the messages are not
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdCollision {
+    /// count on until a free id is found
+    #[default]
+    Skip,
+    /// fail the push with `ErrorKind::InvalidJobId`
+    Error,
+    /// do not check, the new job replaces the stored one
+    Overwrite,
+}
+
+/// The identifier of a job returned by `push`, used by status/remove/delete
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct JobId(String);
+
+impl JobId {
+    /// generate a new ULID based id
+    pub fn ulid() -> Self {
+        JobId(ulid::Ulid::new().to_string())
+    }
+    /// the id as it is stored in redis
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for JobId {
+    type Err = QError;
+    /// parse a job id, it must be non-empty and must not contain whitespace or `;`
+    fn from_str(s: &str) -> QResult<Self> {
+        if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == ';') {
+            return err!(ErrorKind::InvalidJobId, "invalid job id [{}]", s);
+        }
+        Ok(JobId(s.to_string()))
+    }
+}
+
+impl From<u64> for JobId {
+    fn from(id: u64) -> Self {
+        JobId(id.to_string())
+    }
+}
+
+impl ToRedisArgs for JobId {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        out.write_arg(self.0.as_bytes())
+    }
+}
+
+impl FromRedisValue for JobId {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        Ok(JobId(String::from_redis_value(v)?))
+    }
+}
+
+// test job id
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test job id parse and display work
+    #[test]
+    fn test_job_id_parse() {
+        let id: JobId = "42".parse().unwrap();
+        assert_eq!(id, JobId::from(42));
+        assert_eq!(id.to_string(), "42");
+        assert!("".parse::<JobId>().is_err());
+        assert!("4;2".parse::<JobId>().is_err());
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"42\"");
+    }
+    // test ulid ids are unique and valid job ids
+    #[test]
+    fn test_job_id_ulid() {
+        let a = JobId::ulid();
+        let b = JobId::ulid();
+        assert_ne!(a, b);
+        assert_eq!(a.as_str().len(), 26);
+        assert_eq!(a.as_str().parse::<JobId>().unwrap(), a);
+    }
+    // test snowflake ids are ordered and carry the worker id
+    #[test]
+    fn test_snowflake() {
+        let snowflake = Snowflake::with_epoch(5, 0).unwrap();
+        assert_eq!(snowflake.compose(1, 3), (1 << 22) | (5 << 12) | 3);
+        let mut last = 0;
+        for _ in 0..5000 {
+            let id = snowflake.generate().unwrap();
+            assert!(id > last);
+            assert_eq!((id >> 12) & 1023, 5);
+            last = id;
+        }
+        assert!(Snowflake::new(1024).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+use crate::envelope::Envelope;
+use crate::job::JobContext;
+use crate::queue::ReservedJob;
+use crate::QResult;
+use std::fmt::Debug;
+
+/// Runs before every job is stored, see `Queue::intercept_push`. It may add metadata to
+/// the envelope, which workers read in `ReservedJob::metadata`, or reject the push with
+/// an error returned by `push`
+pub trait PushInterceptor: Send + Sync + Debug {
+    /// [envelope] holds the json message and the metadata set by the queue
+    fn before_push(&self, channel: &str, envelope: &mut Envelope) -> QResult<()>;
+}
+
+/// Runs before every job is executed, see `Queue::intercept_execute`
+pub trait ExecuteInterceptor: Send + Sync + Debug {
+    /// decide whether [job] runs, an error fails the job
+    fn before_execute(&self, job: &ReservedJob, ctx: &JobContext) -> QResult<Verdict>;
+}
+
+/// The decision of an `ExecuteInterceptor`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Proceed,
+    /// the job is not executed and deleted like an executed job
+    Veto {
+        reason: String,
+    },
+}
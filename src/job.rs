@@ -1,8 +1,442 @@
-use crate::QResult;
-//use serde::{Deserialize, Serialize};
+#[cfg(feature = "async")]
+use crate::backend::io_error;
+use crate::error::ErrorKind;
+use crate::queue::{Dispatcher, JobOutput};
+use crate::{err, QResult};
+use serde::{Deserialize, Serialize};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use std::sync::OnceLock;
 
 #[typetag::serde(tag = "type")]
-pub trait JobTrait {
+pub trait JobTrait: Send {
+    /// execute the job
     fn execute(&self) -> QResult<()>;
+    /// execute the job with the state of the worker running it, jobs reading the
+    /// state override it, workers always call this one
+    fn execute_with(&self, ctx: &JobContext) -> QResult<()> {
+        let _ = ctx;
+        self.execute()
+    }
+    /// check the job before it is pushed, an error rejects the push so invalid jobs
+    /// fail at enqueue time instead of on a worker
+    fn validate(&self) -> QResult<()> {
+        Ok(())
+    }
+    /// seconds after which the worker abandons the execution, `None` uses the queue default
+    fn execution_timeout(&self) -> Option<u32> {
+        None
+    }
+    /// the name of the rate limit bucket the job draws from, buckets are shared by
+    /// every job type and channel naming them and are configured with `Queue::rate_limit`
+    fn rate_limit_bucket(&self) -> Option<&str> {
+        None
+    }
+    /// the key of the jobs which must not run at the same time, such as `account:42`,
+    /// keys are shared by every job type and channel naming them
+    fn concurrency_key(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The future an `AsyncJobTrait` execution returns, built with `Box::pin(async move { .. })`
+#[cfg(feature = "async")]
+pub type JobFuture<'a> = Pin<Box<dyn Future<Output = QResult<()>> + Send + 'a>>;
+
+/// A job whose execution awaits, such as http calls or database queries on async clients.
+/// Pushed with `Queue::push_async_job`, workers accept it next to `JobTrait` jobs, the
+/// async worker awaits it on its runtime and the others block on one runtime shared by
+/// their threads
+#[cfg(feature = "async")]
+#[typetag::serde(tag = "type")]
+pub trait AsyncJobTrait: Send + Sync {
+    /// execute the job
+    fn execute(&self) -> JobFuture<'_>;
+    /// execute the job with the state of the worker running it, see `JobTrait::execute_with`
+    fn execute_with<'a>(&'a self, ctx: &'a JobContext) -> JobFuture<'a> {
+        let _ = ctx;
+        self.execute()
+    }
+    /// check the job before it is pushed, see `JobTrait::validate`
+    fn validate(&self) -> QResult<()> {
+        Ok(())
+    }
+    /// seconds after which the worker abandons the execution, `None` uses the queue default
+    fn execution_timeout(&self) -> Option<u32> {
+        None
+    }
+    /// the name of the rate limit bucket the job draws from, see `JobTrait::rate_limit_bucket`
+    fn rate_limit_bucket(&self) -> Option<&str> {
+        None
+    }
+    /// the key of the jobs which must not run at the same time, see `JobTrait::concurrency_key`
+    fn concurrency_key(&self) -> Option<String> {
+        None
+    }
 }
 //pub trait SerializeJob: JobTrait + Serialize + Sized + for<'de> Deserialize<'de> + Send {}
+
+/// A job as a producer sees it, its fields and the type name the worker implementation
+/// is registered under, so producers push it with `Queue::push_named` without linking
+/// the worker side of the job and its dependencies
+pub trait NamedJob: Serialize {
+    /// the type name of the worker implementation, the struct name for `#[MakeJob]` impls
+    const TYPE: &'static str;
+}
+
+type FnHandler = Arc<dyn Fn(serde_json::Value, &JobContext) -> QResult<()> + Send + Sync>;
+
+/// The handlers of `FnJob`s by name, registered with `QueueTask::register_fn`
+#[derive(Clone, Default)]
+pub struct FnHandlers {
+    handlers: HashMap<String, FnHandler>,
+}
+
+impl FnHandlers {
+    /// register the handler run for `FnJob`s named [name]
+    pub fn insert<F>(&mut self, name: impl Into<String>, handler: F) -> &mut Self
+    where
+        F: Fn(serde_json::Value, &JobContext) -> QResult<()> + Send + Sync + 'static,
+    {
+        self.handlers.insert(name.into(), Arc::new(handler));
+        self
+    }
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.handlers.contains_key(name)
+    }
+    /// run the handler named [name] with [args]
+    pub fn call(&self, name: &str, args: serde_json::Value, ctx: &JobContext) -> QResult<()> {
+        match self.handlers.get(name) {
+            Some(handler) => handler(args, ctx),
+            None => err!(ErrorKind::NotFound, "no handler registered for [{}]", name),
+        }
+    }
+}
+
+impl fmt::Debug for FnHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.handlers.keys()).finish()
+    }
+}
+
+/// A small job without its own type, it names a handler registered on the worker with
+/// `QueueTask::register_fn` and carries the serialized arguments. Pushed by `Queue::push_fn`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FnJob {
+    pub name: String,
+    pub args: serde_json::Value,
+}
+
+#[typetag::serde]
+impl JobTrait for FnJob {
+    fn execute(&self) -> QResult<()> {
+        self.execute_with(&JobContext::default())
+    }
+    fn execute_with(&self, ctx: &JobContext) -> QResult<()> {
+        let Some(handlers) = ctx.get::<FnHandlers>() else {
+            return err!(
+                ErrorKind::NotFound,
+                "no handler registered for [{}]",
+                self.name
+            );
+        };
+        handlers.call(&self.name, self.args.clone(), ctx)
+    }
+}
+
+/// The handlers of jobs without a rust type, such as jobs pushed by other languages,
+/// keyed by the `type` field of the job, registered with `QueueTask::register_handler`
+#[derive(Debug, Clone, Default)]
+pub struct TypeHandlers(pub FnHandlers);
+
+/// A job whose type has no `JobTrait` implementation, run by its type handler
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RawJob {
+    pub job_type: String,
+    pub value: serde_json::Value,
+}
+
+#[typetag::serde(name = "queue_rs::RawJob")]
+impl JobTrait for RawJob {
+    fn execute(&self) -> QResult<()> {
+        self.execute_with(&JobContext::default())
+    }
+    fn execute_with(&self, ctx: &JobContext) -> QResult<()> {
+        let Some(TypeHandlers(handlers)) = ctx.get::<TypeHandlers>() else {
+            return err!(
+                ErrorKind::NotFound,
+                "no handler registered for [{}]",
+                self.job_type
+            );
+        };
+        handlers.call(&self.job_type, self.value.clone(), ctx)
+    }
+}
+
+/// An `AsyncJobTrait` job run by the workers like the `JobTrait` ones, it waits for the
+/// future on the runtime the async worker put in the context, or on one of its own
+#[cfg(feature = "async")]
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AsyncJob(Box<dyn AsyncJobTrait>);
+
+#[cfg(feature = "async")]
+#[typetag::serde(name = "queue_rs::AsyncJob")]
+impl JobTrait for AsyncJob {
+    fn execute(&self) -> QResult<()> {
+        self.execute_with(&JobContext::default())
+    }
+    fn execute_with(&self, ctx: &JobContext) -> QResult<()> {
+        let execution = self.0.execute_with(ctx);
+        // such as the blocking pool of a runtime
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            return handle.block_on(execution);
+        }
+        shared_runtime()?.block_on(execution)
+    }
+    fn validate(&self) -> QResult<()> {
+        self.0.validate()
+    }
+    fn execution_timeout(&self) -> Option<u32> {
+        self.0.execution_timeout()
+    }
+    fn rate_limit_bucket(&self) -> Option<&str> {
+        self.0.rate_limit_bucket()
+    }
+    fn concurrency_key(&self) -> Option<String> {
+        self.0.concurrency_key()
+    }
+}
+
+/// the runtime the sync workers block on for async jobs, built by the first one
+#[cfg(feature = "async")]
+fn shared_runtime() -> QResult<&'static tokio::runtime::Runtime> {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime);
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(io_error)?;
+    Ok(RUNTIME.get_or_init(|| runtime))
+}
+
+#[cfg(feature = "async")]
+impl AsyncJob {
+    /// read a message no `JobTrait` implementation accepted, `None` if no `AsyncJobTrait`
+    /// implementation accepts it either
+    pub(crate) fn parse(message: &str) -> Option<Self> {
+        serde_json::from_str(message).map(AsyncJob).ok()
+    }
+}
+
+impl RawJob {
+    /// read a message no `JobTrait` implementation accepted, `None` if no handler
+    /// is registered for its type
+    pub(crate) fn parse(message: &str, ctx: &JobContext) -> Option<Self> {
+        let TypeHandlers(handlers) = ctx.get::<TypeHandlers>()?;
+        let value: serde_json::Value = serde_json::from_str(message).ok()?;
+        let job_type = value.get("type")?.as_str()?.to_string();
+        if !handlers.contains(&job_type) {
+            return None;
+        }
+        Some(RawJob { job_type, value })
+    }
+}
+
+/// Values shared by every worker thread of a task and the jobs they execute, looked up
+/// by type like axum extensions, registered with `QueueTask::state`
+#[derive(Clone, Default)]
+pub struct AppState {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl AppState {
+    /// store a value, replacing the previous value of the same type
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> &mut Self {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+        self
+    }
+    /// get the value of a type
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+}
+
+impl fmt::Debug for AppState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AppState")
+            .field("values", &self.values.len())
+            .finish()
+    }
+}
+
+/// State a worker shares with every job it executes, the app state of the task
+/// plus the values `QueueTask::on_start` added for the worker thread
+#[derive(Debug, Clone, Default)]
+pub struct JobContext {
+    state: AppState,
+}
+
+impl JobContext {
+    pub fn new(state: AppState) -> Self {
+        JobContext { state }
+    }
+    /// store a value for this worker, replacing the previous value of the same type
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> &mut Self {
+        self.state.insert(value);
+        self
+    }
+    /// get the value of a type
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.state.get()
+    }
+    /// the dispatcher of the worker, to push follow-up jobs
+    pub fn dispatcher(&self) -> Option<&Dispatcher> {
+        self.get()
+    }
+    /// the output of the running job, read by `Queue::result_stream`
+    pub fn output(&self) -> Option<&JobOutput> {
+        self.get()
+    }
+}
+
+// test job context
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test context values by type work
+    #[test]
+    fn test_job_context() {
+        struct Pool(u32);
+        let mut ctx = JobContext::default();
+        ctx.insert(Pool(4)).insert("api".to_string());
+        assert_eq!(ctx.get::<Pool>().map(|pool| pool.0), Some(4));
+        assert_eq!(ctx.get::<String>().map(String::as_str), Some("api"));
+        assert!(ctx.get::<u64>().is_none());
+        let cloned = ctx.clone();
+        assert_eq!(cloned.get::<Pool>().map(|pool| pool.0), Some(4));
+    }
+    // test the app state is visible from the context work
+    #[test]
+    fn test_app_state() {
+        let mut state = AppState::default();
+        state.insert(7u64);
+        let mut ctx = JobContext::new(state.clone());
+        ctx.insert(8u64);
+        assert_eq!(ctx.get::<u64>(), Some(&8));
+        assert_eq!(state.get::<u64>(), Some(&7));
+    }
+    // test fn job runs its registered handler work
+    #[test]
+    fn test_fn_job() {
+        let mut handlers = FnHandlers::default();
+        handlers.insert("cleanup-tmp", |args, _| match args.as_str() {
+            Some("/tmp") => Ok(()),
+            _ => err!("unexpected args"),
+        });
+        let mut ctx = JobContext::default();
+        ctx.insert(handlers);
+        let job = FnJob {
+            name: "cleanup-tmp".to_string(),
+            args: "/tmp".into(),
+        };
+        let json = serde_json::to_string(&job as &dyn JobTrait).unwrap();
+        let job: Box<dyn JobTrait> = serde_json::from_str(&json).unwrap();
+        assert!(job.execute_with(&ctx).is_ok());
+        let missing = FnJob {
+            name: "missing".to_string(),
+            args: serde_json::Value::Null,
+        };
+        let err = missing.execute_with(&ctx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+    // test an async job is read and run by the sync job path
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_job() {
+        #[derive(Serialize, Deserialize)]
+        struct Ping {
+            delay_ms: u64,
+        }
+        #[typetag::serde]
+        impl AsyncJobTrait for Ping {
+            fn execute(&self) -> JobFuture<'_> {
+                Box::pin(async move {
+                    tokio::time::sleep(std::time::Duration::from_millis(self.delay_ms)).await;
+                    if self.delay_ms > 100 {
+                        return err!(ErrorKind::Timeout, "ping too slow");
+                    }
+                    Ok(())
+                })
+            }
+            fn concurrency_key(&self) -> Option<String> {
+                Some("ping".to_string())
+            }
+        }
+        let message = serde_json::to_string(&Ping { delay_ms: 5 } as &dyn AsyncJobTrait).unwrap();
+        assert!(serde_json::from_str::<Box<dyn JobTrait>>(&message).is_err());
+        let job = AsyncJob::parse(&message).unwrap();
+        assert_eq!(job.concurrency_key().as_deref(), Some("ping"));
+        // without a runtime and from the blocking pool of one
+        assert!(job.execute_with(&JobContext::default()).is_ok());
+        // every worker thread blocks on the one shared runtime
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let message = message.clone();
+                std::thread::spawn(move || {
+                    AsyncJob::parse(&message)
+                        .unwrap()
+                        .execute_with(&JobContext::default())
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap().is_ok());
+        }
+        assert!(std::ptr::eq(
+            shared_runtime().unwrap(),
+            shared_runtime().unwrap()
+        ));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let slow = AsyncJob::parse("{\"type\":\"Ping\",\"delay_ms\":150}").unwrap();
+        let result = runtime
+            .block_on(async {
+                tokio::task::spawn_blocking(move || slow.execute_with(&JobContext::default())).await
+            })
+            .unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Timeout);
+        assert!(AsyncJob::parse("{\"type\":\"Unknown\"}").is_none());
+    }
+    // test a job without rust type runs its type handler work
+    #[test]
+    fn test_raw_job() {
+        let message = "{\"type\":\"SendEmail\",\"to\":\"a@b.c\"}";
+        let mut ctx = JobContext::default();
+        assert!(RawJob::parse(message, &ctx).is_none());
+        let mut handlers = FnHandlers::default();
+        handlers.insert("SendEmail", |value, _| match value["to"].as_str() {
+            Some("a@b.c") => Ok(()),
+            _ => err!("unexpected recipient"),
+        });
+        ctx.insert(TypeHandlers(handlers));
+        let job = RawJob::parse(message, &ctx).unwrap();
+        assert_eq!(job.job_type, "SendEmail");
+        assert!(job.execute_with(&ctx).is_ok());
+        assert!(RawJob::parse("{\"type\":\"Other\"}", &ctx).is_none());
+    }
+}
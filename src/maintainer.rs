@@ -0,0 +1,140 @@
+use crate::queue::Queue;
+use crate::QResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Moves expired delayed and reserved jobs into the waiting list on its own cadence,
+/// out of the reserve path. Any number may run, a lease in redis elects the one doing
+/// the work, so run one next to every worker group and turn `inline_maintenance` off
+#[derive(Debug)]
+pub struct Maintainer {
+    queue: Queue,
+    /// time between two passes
+    interval: Duration,
+    /// how long the leader keeps the lease without renewing it
+    lease: Duration,
+    /// identifies this maintainer as the owner of the lease
+    token: String,
+    stopping: AtomicBool,
+}
+
+impl Maintainer {
+    pub fn new(queue: Queue) -> Self {
+        Maintainer {
+            queue,
+            interval: Duration::from_secs(1),
+            lease: Duration::from_secs(10),
+            token: ulid::Ulid::new().to_string(),
+            stopping: AtomicBool::new(false),
+        }
+    }
+    /// set the time between two passes, delayed jobs start up to that late
+    pub fn interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = interval;
+        self
+    }
+    /// set how long the leader keeps the lease without renewing it, another maintainer
+    /// takes over that long after the leader died. Keep it above the interval
+    pub fn lease(&mut self, lease: Duration) -> &mut Self {
+        self.lease = lease;
+        self
+    }
+    /// stop `run` after the current pass
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+    /// run passes until `stop` is called, errors are logged and the loop goes on
+    pub fn run(&self) -> QResult<()> {
+        let mut leader = false;
+        while !self.stopping.load(Ordering::SeqCst) {
+            match self.pass() {
+                Ok(is_leader) => {
+                    if is_leader != leader {
+                        leader = is_leader;
+                        info!(
+                            "Maintainer [{}] leader state changed to [{}]",
+                            self.token, leader
+                        );
+                    }
+                }
+                Err(e) => error!("{}", e),
+            }
+            thread::sleep(self.interval);
+        }
+        if leader {
+            self.release()?;
+        }
+        Ok(())
+    }
+    /// take or renew the lease and move the expired jobs if this maintainer is the leader
+    pub fn pass(&self) -> QResult<bool> {
+        let mut conn = self.queue.connection()?;
+        let script = redis::Script::new(
+            r"
+            if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+                return 1
+            end
+            if redis.call('GET', KEYS[1]) == ARGV[1] then
+                redis.call('PEXPIRE', KEYS[1], ARGV[2])
+                return 1
+            end
+            return 0
+            ",
+        );
+        let leader: bool = script
+            .key(self.queue.maintainer_key())
+            .arg(&self.token)
+            .arg(self.lease.as_millis() as u64)
+            .invoke(&mut conn)?;
+        if leader {
+            self.queue.maintain(&mut conn)?;
+        }
+        Ok(leader)
+    }
+    /// give the lease up so another maintainer takes over without waiting for it to expire
+    fn release(&self) -> QResult<()> {
+        let mut conn = self.queue.connection()?;
+        let script = redis::Script::new(
+            r"
+            if redis.call('GET', KEYS[1]) == ARGV[1] then
+                return redis.call('DEL', KEYS[1])
+            end
+            return 0
+            ",
+        );
+        script
+            .key(self.queue.maintainer_key())
+            .arg(&self.token)
+            .invoke::<()>(&mut conn)?;
+        Ok(())
+    }
+}
+
+// test maintainer
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test only one maintainer holds the lease
+    #[test]
+    fn test_maintainer_lease() {
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let first = Maintainer::new(Queue::new("test-maintainer", client.clone()));
+        let second = Maintainer::new(Queue::new("test-maintainer", client));
+        assert!(first.pass().unwrap());
+        assert!(!second.pass().unwrap());
+        first.release().unwrap();
+        assert!(second.pass().unwrap());
+        second.release().unwrap();
+    }
+    // test a stopped maintainer returns without a pass
+    #[test]
+    fn test_maintainer_stop() {
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let maintainer = Maintainer::new(Queue::new("test-maintainer", client));
+        maintainer.stop();
+        assert!(maintainer.run().is_ok());
+    }
+}
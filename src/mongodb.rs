@@ -0,0 +1,242 @@
+use crate::backend::QueueBackend;
+use crate::error::{ErrorKind, QError};
+use crate::id::JobId;
+use crate::queue::{ReservedJob, STATUS_DONE, STATUS_RESERVED, STATUS_WAITING};
+use crate::{err, QResult};
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::{IndexOptions, ReturnDocument};
+use mongodb::sync::{Client, Collection};
+use mongodb::IndexModel;
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// how long `reserve` waits between two polls of the collection
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// how long a done job is kept so `status` reports it done, the TTL index removes it after
+const DONE_RETENTION: Duration = Duration::from_secs(3600);
+
+/// the `reserved_until` of a job nobody reserved, so due jobs and jobs whose reservation
+/// expired are matched by the same range
+const UNRESERVED: DateTime = DateTime::from_millis(0);
+
+/// A queue keeping jobs in a mongodb collection, for applications whose only datastore is
+/// mongodb. Every channel shares the `queue_rs_jobs` collection of the default database of
+/// the uri, `queue_rs` when it names none. Workers reserve a job with one
+/// `findOneAndUpdate`, which also takes over a reservation past its `reserved_until`, so
+/// the job of a killed worker is recovered once its ttr expired. A done job is only marked
+/// done, and a TTL index on `done_at` removes it after an hour
+#[derive(Debug)]
+pub struct MongoQueue {
+    jobs: Collection<Document>,
+    channel: String,
+    /// the seconds a reservation lasts
+    ttr: u32,
+    /// the seconds a pushed job waits before it can be reserved
+    delay: u32,
+}
+
+/// a mongodb error as a queue error
+fn mongo_error(e: mongodb::error::Error) -> QError {
+    QError::new(ErrorKind::Other, e.to_string())
+}
+
+/// the time [seconds] from now
+fn after(seconds: u32) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() + seconds as i64 * 1000)
+}
+
+impl MongoQueue {
+    /// a queue on [channel] of the mongodb at [uri] such as `mongodb://127.0.0.1/app`, the
+    /// indexes of the collection are created if they do not exist
+    pub fn connect(uri: &str, channel: impl Into<String>, ttr: u32) -> QResult<Self> {
+        let client = Client::with_uri_str(uri).map_err(mongo_error)?;
+        let database = client
+            .default_database()
+            .unwrap_or_else(|| client.database("queue_rs"));
+        let jobs = database.collection::<Document>("queue_rs_jobs");
+        jobs.create_index(
+            IndexModel::builder()
+                .keys(doc! { "channel": 1, "reserved_until": 1, "due_at": 1 })
+                .build(),
+        )
+        .run()
+        .map_err(mongo_error)?;
+        jobs.create_index(
+            IndexModel::builder()
+                .keys(doc! { "done_at": 1 })
+                .options(IndexOptions::builder().expire_after(DONE_RETENTION).build())
+                .build(),
+        )
+        .run()
+        .map_err(mongo_error)?;
+        Ok(MongoQueue {
+            jobs,
+            channel: channel.into(),
+            ttr,
+            delay: 0,
+        })
+    }
+    /// Set the seconds a pushed job waits before it can be reserved
+    pub fn delay(&mut self, delay: u32) -> &mut Self {
+        self.delay = delay;
+        self
+    }
+    /// reserve the first due job which is not reserved or whose reservation expired
+    fn try_reserve(&self) -> QResult<Option<ReservedJob>> {
+        let now = DateTime::now();
+        let token = ulid::Ulid::new().to_string();
+        let job = self
+            .jobs
+            .find_one_and_update(
+                doc! {
+                    "channel": &self.channel,
+                    "reserved_until": { "$lte": now },
+                    "due_at": { "$lte": now },
+                    "done_at": { "$exists": false },
+                },
+                doc! {
+                    "$set": { "reserved_until": after(self.ttr), "token": &token },
+                    "$inc": { "attempts": 1 },
+                },
+            )
+            .sort(doc! { "due_at": 1, "_id": 1 })
+            .return_document(ReturnDocument::After)
+            .run()
+            .map_err(mongo_error)?;
+        let Some(job) = job else {
+            return Ok(None);
+        };
+        let (Ok(id), Ok(message), Ok(attempts)) = (
+            job.get_str("_id"),
+            job.get_str("message"),
+            job.get_i32("attempts"),
+        ) else {
+            return err!(
+                ErrorKind::InvalidPayload,
+                "malformed job document {:?}",
+                job
+            );
+        };
+        Ok(Some(ReservedJob {
+            id: id.parse()?,
+            message: message.to_string(),
+            ttr: self.ttr,
+            attempts: attempts as u32,
+            token,
+            metadata: BTreeMap::new(),
+        }))
+    }
+}
+
+impl QueueBackend for MongoQueue {
+    fn push_message(&self, message: String) -> QResult<JobId> {
+        let id = JobId::ulid();
+        self.jobs
+            .insert_one(doc! {
+                "_id": id.as_str(),
+                "channel": &self.channel,
+                "message": message,
+                "due_at": after(self.delay),
+                "reserved_until": UNRESERVED,
+                "attempts": 0,
+            })
+            .run()
+            .map_err(mongo_error)?;
+        Ok(id)
+    }
+    fn reserve(&self, timeout: u64) -> QResult<ReservedJob> {
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        loop {
+            if let Some(job) = self.try_reserve()? {
+                return Ok(job);
+            }
+            if Instant::now() >= deadline {
+                return err!(ErrorKind::NotFound, "No job found");
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    fn delete(&self, id: &JobId, token: &str) -> QResult<bool> {
+        let done = self
+            .jobs
+            .update_one(
+                doc! {
+                    "_id": id.as_str(),
+                    "channel": &self.channel,
+                    "token": token,
+                    "done_at": { "$exists": false },
+                },
+                doc! {
+                    "$set": { "done_at": DateTime::now() },
+                    "$unset": { "token": "", "message": "" },
+                },
+            )
+            .run()
+            .map_err(mongo_error)?;
+        Ok(done.modified_count == 1)
+    }
+    fn move_expired(&self) -> QResult<()> {
+        self.jobs
+            .update_many(
+                doc! {
+                    "channel": &self.channel,
+                    "reserved_until": { "$gt": UNRESERVED, "$lte": DateTime::now() },
+                    "done_at": { "$exists": false },
+                },
+                doc! {
+                    "$set": { "reserved_until": UNRESERVED },
+                    "$unset": { "token": "" },
+                },
+            )
+            .run()
+            .map_err(mongo_error)?;
+        Ok(())
+    }
+    fn status(&self, id: &JobId) -> QResult<u8> {
+        let job = self
+            .jobs
+            .find_one(doc! { "_id": id.as_str(), "channel": &self.channel })
+            .run()
+            .map_err(mongo_error)?;
+        let status = match job {
+            None => STATUS_DONE,
+            Some(job) if job.contains_key("done_at") => STATUS_DONE,
+            Some(job) if job.contains_key("token") => STATUS_RESERVED,
+            Some(_) => STATUS_WAITING,
+        };
+        Ok(status)
+    }
+    fn clear(&self) -> QResult<()> {
+        self.jobs
+            .delete_many(doc! { "channel": &self.channel })
+            .run()
+            .map_err(mongo_error)?;
+        Ok(())
+    }
+    fn name(&self) -> &str {
+        &self.channel
+    }
+}
+
+// test mongodb
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test a delay is counted from now
+    #[test]
+    fn test_after() {
+        let now = DateTime::now().timestamp_millis();
+        let due = after(60).timestamp_millis();
+        assert!((now + 60_000..now + 61_000).contains(&due));
+        assert!(UNRESERVED < DateTime::now());
+    }
+    // test an unreachable server is an error
+    #[test]
+    fn test_unreachable() {
+        let uri = "mongodb://127.0.0.1:1/app?serverSelectionTimeoutMS=200";
+        assert!(MongoQueue::connect(uri, "emails", 60).is_err());
+    }
+}
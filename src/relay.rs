@@ -0,0 +1,128 @@
+use crate::error::ErrorKind;
+use crate::queue::Queue;
+use crate::{err, QResult};
+use redis::Commands;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error};
+
+/// streams with their entries, an entry is its id and flat field value pairs
+type XReadReply = Vec<(String, Vec<(String, Vec<String>)>)>;
+
+/// Replays the pushes a queue appended to its replication stream (`Queue::replicate_to`)
+/// into the same channels of another redis, keeping a warm standby for disaster recovery.
+/// The position in the stream is kept in the target redis, so a restarted relay resumes
+/// where it stopped, a push may be replayed twice after a crash
+#[derive(Debug)]
+pub struct Relay {
+    source: redis::Client,
+    stream: String,
+    target: redis::Client,
+    /// how long a read waits for new pushes
+    block: Duration,
+    stopping: AtomicBool,
+}
+
+impl Relay {
+    pub fn new(source: redis::Client, stream: impl Into<String>, target: redis::Client) -> Self {
+        Relay {
+            source,
+            stream: stream.into(),
+            target,
+            block: Duration::from_secs(1),
+            stopping: AtomicBool::new(false),
+        }
+    }
+    /// stop `run` after the current batch
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+    /// relay pushes until `stop` is called, errors are logged and retried a second later
+    pub fn run(&self) {
+        while !self.stopping.load(Ordering::SeqCst) {
+            if let Err(e) = self.relay(100) {
+                error!("{}", e);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+    /// relay up to [count] pushes, waiting a second for new ones, return how many were relayed
+    pub fn relay(&self, count: usize) -> QResult<usize> {
+        let mut source = self.source.get_connection()?;
+        let mut target = self.target.get_connection()?;
+        let cursor = format!("{}.relayed", self.stream);
+        let last: Option<String> = target.get(&cursor)?;
+        let reply: Option<XReadReply> = redis::cmd("XREAD")
+            .arg("COUNT")
+            .arg(count)
+            .arg("BLOCK")
+            .arg(self.block.as_millis() as u64)
+            .arg("STREAMS")
+            .arg(&self.stream)
+            .arg(last.as_deref().unwrap_or("0-0"))
+            .query(&mut source)?;
+        let mut relayed = 0;
+        for (_, entries) in reply.unwrap_or_default() {
+            for (id, fields) in entries {
+                let (channel, delay, message) = parse_entry(fields)?;
+                let mut queue = Queue::new(channel, self.target.clone());
+                queue.delay(delay);
+                let job_id = queue.push_raw(message)?;
+                target.set::<_, _, ()>(&cursor, &id)?;
+                debug!("Relayed push [{}] as job id:[{}]", id, job_id);
+                relayed += 1;
+            }
+        }
+        Ok(relayed)
+    }
+}
+
+/// read the channel, delay and message of a stream entry
+fn parse_entry(fields: Vec<String>) -> QResult<(String, u32, String)> {
+    let mut values = HashMap::new();
+    let mut fields = fields.into_iter();
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        values.insert(name, value);
+    }
+    let (Some(channel), Some(message)) = (values.remove("channel"), values.remove("message"))
+    else {
+        return err!(
+            ErrorKind::InvalidPayload,
+            "replication entry without channel or message"
+        );
+    };
+    let delay = values
+        .get("delay")
+        .and_then(|delay| delay.parse().ok())
+        .unwrap_or(0);
+    Ok((channel, delay, message))
+}
+
+// test relay
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test parse replication entry work
+    #[test]
+    fn test_parse_entry() {
+        let fields = [
+            "channel",
+            "mail",
+            "delay",
+            "10",
+            "message",
+            "{\"type\":\"TestJob\"}",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let (channel, delay, message) = parse_entry(fields).unwrap();
+        assert_eq!(channel, "mail");
+        assert_eq!(delay, 10);
+        assert_eq!(message, "{\"type\":\"TestJob\"}");
+        assert!(parse_entry(vec!["channel".to_string(), "mail".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::error::ErrorKind;
+use crate::{err, QResult};
+use redis::{ConnectionAddr, ConnectionInfo, FromRedisValue, RedisConnectionInfo};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Follows the master of a redis sentinel deployment. Each connection checks that the known
+/// master still is one, after a failover the sentinels are asked for the new master, so
+/// a queue built with `Queue::with_sentinel` keeps working once the failover is done
+#[derive(Debug)]
+pub struct Sentinel {
+    sentinels: Vec<redis::Client>,
+    /// the name the sentinels monitor the master under
+    master: String,
+    /// the database and credentials of the master
+    info: RedisConnectionInfo,
+    /// how long connecting to a sentinel or the master may take
+    timeout: Duration,
+    /// the client of the last known master
+    current: Mutex<Option<redis::Client>>,
+}
+
+impl Sentinel {
+    /// [sentinels] are the urls of the sentinels, such as `redis://10.0.0.1:26379/`
+    pub fn new(sentinels: &[&str], master: impl Into<String>) -> QResult<Self> {
+        let sentinels = sentinels
+            .iter()
+            .map(|url| redis::Client::open(*url))
+            .collect::<Result<Vec<_>, _>>()?;
+        if sentinels.is_empty() {
+            return err!(ErrorKind::Other, "no sentinel given");
+        }
+        Ok(Sentinel {
+            sentinels,
+            master: master.into(),
+            info: RedisConnectionInfo::default(),
+            timeout: Duration::from_secs(2),
+            current: Mutex::new(None),
+        })
+    }
+    /// Set the database and credentials used on the master
+    pub fn master_info(&mut self, info: RedisConnectionInfo) -> &mut Self {
+        self.info = info;
+        self
+    }
+    /// Set how long connecting to a sentinel or the master may take
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+    /// the client of the current master, asking the sentinels if none is known yet
+    pub fn client(&self) -> QResult<redis::Client> {
+        let mut current = self.current.lock().unwrap();
+        if let Some(client) = &*current {
+            return Ok(client.clone());
+        }
+        let client = self.resolve()?;
+        *current = Some(client.clone());
+        Ok(client)
+    }
+    /// a connection to the master, the sentinels are asked again when the known master
+    /// is unreachable or was demoted to a replica
+    pub fn connection(&self) -> QResult<redis::Connection> {
+        let client = self.client()?;
+        if let Some(conn) = self.master_connection(&client) {
+            return Ok(conn);
+        }
+        warn!("Master [{}] moved, asking the sentinels", self.master);
+        let client = self.resolve()?;
+        *self.current.lock().unwrap() = Some(client.clone());
+        match self.master_connection(&client) {
+            Some(conn) => Ok(conn),
+            None => err!(
+                ErrorKind::Redis,
+                "master [{}] given by the sentinels is not reachable as a master",
+                self.master
+            ),
+        }
+    }
+    /// connect to [client] if it answers as a master
+    fn master_connection(&self, client: &redis::Client) -> Option<redis::Connection> {
+        let mut conn = client.get_connection_with_timeout(self.timeout).ok()?;
+        let role: Vec<redis::Value> = redis::cmd("ROLE").query(&mut conn).ok()?;
+        let role = String::from_redis_value(role.first()?).ok()?;
+        (role == "master").then_some(conn)
+    }
+    /// ask the sentinels in turn for the address of the master
+    fn resolve(&self) -> QResult<redis::Client> {
+        for sentinel in &self.sentinels {
+            let Ok(mut conn) = sentinel.get_connection_with_timeout(self.timeout) else {
+                continue;
+            };
+            let addr: Option<(String, u16)> = match redis::cmd("SENTINEL")
+                .arg("get-master-addr-by-name")
+                .arg(&self.master)
+                .query(&mut conn)
+            {
+                Ok(addr) => addr,
+                Err(_) => continue,
+            };
+            if let Some((host, port)) = addr {
+                info!("Master [{}] is at [{}:{}]", self.master, host, port);
+                return Ok(redis::Client::open(ConnectionInfo {
+                    addr: ConnectionAddr::Tcp(host, port),
+                    redis: self.info.clone(),
+                })?);
+            }
+        }
+        err!(
+            ErrorKind::Redis,
+            "no sentinel knows the master [{}]",
+            self.master
+        )
+    }
+}
+
+// test sentinel
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test an unreachable deployment is an error
+    #[test]
+    fn test_no_sentinel() {
+        assert!(Sentinel::new(&[], "mymaster").is_err());
+        let mut sentinel = Sentinel::new(&["redis://127.0.0.1:1/"], "mymaster").unwrap();
+        sentinel.timeout(Duration::from_millis(100));
+        let Err(e) = sentinel.connection() else {
+            panic!("connected without a sentinel");
+        };
+        assert_eq!(e.kind(), ErrorKind::Redis);
+    }
+}
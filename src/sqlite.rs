@@ -0,0 +1,255 @@
+use crate::backend::QueueBackend;
+use crate::error::{ErrorKind, QError};
+use crate::id::JobId;
+use crate::queue::{ReservedJob, STATUS_DONE, STATUS_RESERVED, STATUS_WAITING};
+use crate::{err, timestamp, QResult};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// how long `reserve` waits between two polls of the table
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A queue keeping jobs in a sqlite database, durable without running a server, and
+/// shared by the processes opening the same file. Every channel shares the
+/// `queue_rs_jobs` table, where `delayed` is the time a job becomes due and `reserved`
+/// the time its reservation expires, both indexed so reserving does not scan the
+/// table. `reserve` moves the expired reservations back before it looks for a job
+#[derive(Debug)]
+pub struct SqliteQueue {
+    channel: String,
+    /// the seconds a reservation lasts
+    ttr: u32,
+    /// the seconds a pushed job waits before it can be reserved
+    delay: u32,
+    conn: Mutex<Connection>,
+}
+
+/// a sqlite error as a queue error
+fn sqlite_error(e: rusqlite::Error) -> QError {
+    QError::new(ErrorKind::Other, e.to_string())
+}
+
+/// the row id of a job id, `None` for an id this backend never gave out
+fn row_id(id: &JobId) -> Option<i64> {
+    id.as_str().parse().ok()
+}
+
+impl SqliteQueue {
+    /// open the queue of [channel] in the database at [path], created with its table if it
+    /// does not exist, `:memory:` keeps the jobs in the memory of the process
+    pub fn open(path: impl AsRef<Path>, channel: impl Into<String>, ttr: u32) -> QResult<Self> {
+        let conn = Connection::open(path).map_err(sqlite_error)?;
+        // readers do not block the writer, and the processes sharing the file wait for
+        // each other's writes instead of failing
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+            .map_err(sqlite_error)?;
+        conn.busy_timeout(Duration::from_secs(5))
+            .map_err(sqlite_error)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS queue_rs_jobs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                channel TEXT NOT NULL,
+                message TEXT NOT NULL,
+                delayed INTEGER NOT NULL,
+                reserved INTEGER,
+                token TEXT,
+                attempts INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE INDEX IF NOT EXISTS queue_rs_jobs_delayed
+                ON queue_rs_jobs (channel, delayed);
+            CREATE INDEX IF NOT EXISTS queue_rs_jobs_reserved
+                ON queue_rs_jobs (channel, reserved);",
+        )
+        .map_err(sqlite_error)?;
+        Ok(SqliteQueue {
+            channel: channel.into(),
+            ttr,
+            delay: 0,
+            conn: Mutex::new(conn),
+        })
+    }
+    /// Set the seconds a pushed job waits before it can be reserved
+    pub fn delay(&mut self, delay: u32) -> &mut Self {
+        self.delay = delay;
+        self
+    }
+    /// reserve the first due job which is not reserved, the update is one statement so
+    /// no other connection reserves the same row
+    fn try_reserve(&self) -> QResult<Option<ReservedJob>> {
+        let now = timestamp()? as i64;
+        let token = ulid::Ulid::new().to_string();
+        let row = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "UPDATE queue_rs_jobs SET reserved = ?2 + ?3, token = ?4, attempts = attempts + 1
+                WHERE id = (
+                    SELECT id FROM queue_rs_jobs
+                    WHERE channel = ?1 AND delayed <= ?2 AND reserved IS NULL
+                    ORDER BY delayed, id LIMIT 1
+                )
+                RETURNING id, message, attempts",
+                params![self.channel, now, self.ttr, token],
+                |row| Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(sqlite_error)?;
+        Ok(row.map(|(id, message, attempts)| ReservedJob {
+            id: JobId::from(id as u64),
+            message,
+            ttr: self.ttr,
+            attempts,
+            token,
+            metadata: BTreeMap::new(),
+        }))
+    }
+}
+
+impl QueueBackend for SqliteQueue {
+    fn push_message(&self, message: String) -> QResult<JobId> {
+        let delayed = timestamp()? as i64 + self.delay as i64;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO queue_rs_jobs (channel, message, delayed) VALUES (?1, ?2, ?3)",
+            params![self.channel, message, delayed],
+        )
+        .map_err(sqlite_error)?;
+        Ok(JobId::from(conn.last_insert_rowid() as u64))
+    }
+    fn reserve(&self, timeout: u64) -> QResult<ReservedJob> {
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        loop {
+            self.move_expired()?;
+            if let Some(job) = self.try_reserve()? {
+                return Ok(job);
+            }
+            if Instant::now() >= deadline {
+                return err!(ErrorKind::NotFound, "No job found");
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    fn delete(&self, id: &JobId, token: &str) -> QResult<bool> {
+        let Some(id) = row_id(id) else {
+            return Ok(false);
+        };
+        let deleted = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM queue_rs_jobs WHERE id = ?1 AND channel = ?2 AND token = ?3",
+                params![id, self.channel, token],
+            )
+            .map_err(sqlite_error)?;
+        Ok(deleted == 1)
+    }
+    fn move_expired(&self) -> QResult<()> {
+        let now = timestamp()? as i64;
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE queue_rs_jobs SET reserved = NULL, token = NULL
+                WHERE channel = ?1 AND reserved <= ?2",
+                params![self.channel, now],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+    fn status(&self, id: &JobId) -> QResult<u8> {
+        let Some(id) = row_id(id) else {
+            return Ok(STATUS_DONE);
+        };
+        let reserved = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT reserved IS NOT NULL FROM queue_rs_jobs WHERE id = ?1 AND channel = ?2",
+                params![id, self.channel],
+                |row| row.get::<_, bool>(0),
+            )
+            .optional()
+            .map_err(sqlite_error)?;
+        let status = match reserved {
+            None => STATUS_DONE,
+            Some(true) => STATUS_RESERVED,
+            Some(false) => STATUS_WAITING,
+        };
+        Ok(status)
+    }
+    fn clear(&self) -> QResult<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM queue_rs_jobs WHERE channel = ?1",
+                params![self.channel],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+    fn name(&self) -> &str {
+        &self.channel
+    }
+}
+
+// test sqlite
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test a delayed job waits and channels sharing a database keep their own jobs
+    #[test]
+    fn test_delay_channels() {
+        let path = std::env::temp_dir().join(format!("queue-rs-sqlite-{}", std::process::id()));
+        let mut emails = SqliteQueue::open(&path, "emails", 60).unwrap();
+        let reports = SqliteQueue::open(&path, "reports", 60).unwrap();
+        emails.delay(1);
+        let id = emails.push_message("{}".to_string()).unwrap();
+        assert_eq!(emails.status(&id).unwrap(), STATUS_WAITING);
+        assert_eq!(emails.reserve(0).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(reports.status(&id).unwrap(), STATUS_DONE);
+        assert!(reports.reserve(0).is_err());
+        let job = emails.reserve(3).unwrap();
+        assert_eq!(job.id, id);
+        assert_eq!(emails.status(&id).unwrap(), STATUS_RESERVED);
+        assert!(!reports.delete(&id, &job.token).unwrap());
+        assert!(emails.delete(&id, &job.token).unwrap());
+        drop((emails, reports));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+    // test reserving looks jobs up through the delayed and reserved indexes
+    #[test]
+    fn test_indexed() {
+        let queue = SqliteQueue::open(":memory:", "emails", 60).unwrap();
+        let conn = queue.conn.lock().unwrap();
+        let plan = |sql: &str| -> String {
+            let mut stmt = conn
+                .prepare(&format!("EXPLAIN QUERY PLAN {}", sql))
+                .unwrap();
+            let details = stmt
+                .query_map(params!["emails", 0], |row| row.get::<_, String>(3))
+                .unwrap();
+            details.map(Result::unwrap).collect::<Vec<_>>().join("\n")
+        };
+        let due = plan(
+            "SELECT id FROM queue_rs_jobs WHERE channel = ?1 AND delayed <= ?2 AND reserved IS NULL",
+        );
+        assert!(due.contains("INDEX queue_rs_jobs_"), "{}", due);
+        let expired = plan("SELECT id FROM queue_rs_jobs WHERE channel = ?1 AND reserved <= ?2");
+        assert!(
+            expired.contains("INDEX queue_rs_jobs_reserved"),
+            "{}",
+            expired
+        );
+    }
+}
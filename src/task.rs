@@ -1,77 +1,772 @@
-use crate::queue::Queue;
-use crate::{QError, QResult};
+use crate::backend::QueueBackend;
+use crate::envelope;
+use crate::error::ErrorKind;
+use crate::id::JobId;
+use crate::job::{AppState, FnHandlers, JobContext, TypeHandlers};
+use crate::queue::{Queue, ReservedJob, Verbosity};
+#[cfg(feature = "webhook")]
+use crate::webhook::{Webhook, WebhookEvent};
+use crate::{timestamp, QError, QResult};
+use serde::Serialize;
+use std::any::Any;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 
+/// a job the worker is executing right now
+#[derive(Debug, Clone, Serialize)]
+pub struct InFlightJob {
+    pub id: JobId,
+    pub attempts: u32,
+    /// unix timestamp when the execution started
+    pub started_at: u64,
+}
+
+type InFlight = Arc<Mutex<Vec<InFlightJob>>>;
+
+/// why a worker stopped
+#[derive(Debug, Serialize)]
+pub enum ExitCause {
+    /// `run` found no more jobs
+    Drained,
+    /// `stop` was called
+    Stopped,
+    /// `run` found the channel paused, such as by the bury limit after a burst of failures
+    Paused,
+    /// an error ended `run`
+    Error(QError),
+}
+
+/// the summary a worker returns when it stops
+#[derive(Debug, Serialize)]
+pub struct WorkerReport {
+    /// the number of jobs executed
+    pub processed: u64,
+    /// the number of jobs which failed, panicked or could not be handled
+    pub failed: u64,
+    /// unix timestamp when the worker started
+    pub started_at: u64,
+    pub duration: Duration,
+    pub cause: ExitCause,
+}
+
+impl WorkerReport {
+    pub(crate) fn start() -> Self {
+        WorkerReport {
+            processed: 0,
+            failed: 0,
+            started_at: timestamp().unwrap_or_default(),
+            duration: Duration::ZERO,
+            cause: ExitCause::Stopped,
+        }
+    }
+    pub(crate) fn finish(mut self, started: Instant, cause: ExitCause) -> Self {
+        self.duration = started.elapsed();
+        self.cause = cause;
+        info!(
+            "Worker stopped after [{}] jobs, [{}] failed, cause: {:?}",
+            self.processed, self.failed, self.cause
+        );
+        self
+    }
+}
+
+/// What a worker does with a job whose payload does not deserialize into any job type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecodeFailure {
+    /// leave it reserved, it is delivered again once its ttr expires, for workers
+    /// deployed before the job type is registered
+    Retry,
+    /// bury it for inspection
+    #[default]
+    Park,
+    /// delete it, an error event is logged with its payload
+    Drop,
+}
+
+/// apply [policy] to a job which failed to deserialize with [e]
+pub(crate) fn undecodable<B: QueueBackend + ?Sized>(
+    queue: &B,
+    job: &ReservedJob,
+    policy: DecodeFailure,
+    e: QError,
+) -> QResult<()> {
+    match policy {
+        DecodeFailure::Retry => {
+            warn!("Undecodable job id:[{}] left for a retry: {}", job.id, e);
+        }
+        DecodeFailure::Park => {
+            warn!("Undecodable job id:[{}] buried: {}", job.id, e);
+            queue.bury(&job.id)?;
+        }
+        DecodeFailure::Drop => {
+            error!(
+                "Undecodable job id:[{}] dropped: {} message:[{}]",
+                job.id, e, job.message
+            );
+            queue.delete(&job.id, &job.token)?;
+        }
+    }
+    Ok(())
+}
+
+/// the least time between two changes of the process title
+const TITLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shows the state of a worker as the title of its process, such as
+/// `queue-rs: emails [3 busy/5] last=SendEmail`, printed by `ps` and `top`,
+/// see `QueueTask::process_title`
 #[derive(Debug)]
-pub struct QueueTask {
-    pub inner: Arc<Mutex<Queue>>,
+pub(crate) struct ProcessTitle {
+    channel: String,
+    /// the type of the last job the worker started
+    last: Option<String>,
+    /// the title shown and when it was set
+    shown: Option<(String, Instant)>,
+}
+
+impl ProcessTitle {
+    pub(crate) fn new(channel: &str) -> Self {
+        ProcessTitle {
+            channel: channel.to_string(),
+            last: None,
+            shown: None,
+        }
+    }
+    /// record [job] as the last job the worker started
+    pub(crate) fn started(&mut self, job: &ReservedJob) {
+        self.last = Some(envelope::job_type(&job.message).unwrap_or_else(|| "untyped".to_string()));
+    }
+    /// show [busy] jobs executing out of [concurrency], at most once per `TITLE_INTERVAL`
+    /// so a busy worker does not rewrite its title on every job
+    pub(crate) fn show(&mut self, busy: usize, concurrency: usize) {
+        let title = self.title(busy, concurrency);
+        if let Some((shown, at)) = &self.shown {
+            if *shown == title || at.elapsed() < TITLE_INTERVAL {
+                return;
+            }
+        }
+        if let Err(e) = set_process_title(&title) {
+            debug!("Process title not updated: {}", e);
+        }
+        self.shown = Some((title, Instant::now()));
+    }
+    fn title(&self, busy: usize, concurrency: usize) -> String {
+        let mut title = format!("queue-rs: {} [{} busy/{}]", self.channel, busy, concurrency);
+        if let Some(last) = &self.last {
+            title.push_str(" last=");
+            title.push_str(last);
+        }
+        title
+    }
+}
+
+/// [title] cut to at most [max] bytes on a char boundary
+#[cfg(target_os = "linux")]
+fn cut(title: &str, max: usize) -> &str {
+    let mut end = title.len().min(max);
+    while !title.is_char_boundary(end) {
+        end -= 1;
+    }
+    &title[..end]
 }
-impl QueueTask {
-    /// init a queue by channel and redis client
-    pub fn new(queue: Queue) -> Self {
+
+/// overwrite the command line of the process with [title], cut to the length of the
+/// original command line. `std::env::args` read afterwards returns the title and empty
+/// arguments
+#[cfg(target_os = "linux")]
+fn set_process_title(title: &str) -> std::io::Result<()> {
+    use std::io;
+    use std::os::unix::fs::FileExt;
+
+    let stat = std::fs::read_to_string("/proc/self/stat")?;
+    // the fields after the command name, which may hold spaces and parentheses, start
+    // with the third, `arg_start` and `arg_end` are the 48th and 49th
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .map(|(_, fields)| fields.split_whitespace().collect())
+        .unwrap_or_default();
+    let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+    let (Some(start), Some(end)) = (field(48), field(49)) else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no command line bounds in /proc/self/stat",
+        ));
+    };
+    // the rest of the original arguments is blanked, the last byte stays a nul
+    let mut line = vec![0; end.saturating_sub(start) as usize];
+    let title = cut(title, line.len().saturating_sub(1));
+    line[..title.len()].copy_from_slice(title.as_bytes());
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open("/proc/self/mem")?
+        .write_all_at(&line, start)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_process_title(_title: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// track a job as in flight until the guard is dropped
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    id: JobId,
+}
+
+impl<'a> InFlightGuard<'a> {
+    fn new(in_flight: &'a InFlight, job: &ReservedJob) -> Self {
+        let job = InFlightJob {
+            id: job.id.clone(),
+            attempts: job.attempts,
+            started_at: timestamp().unwrap_or_default(),
+        };
+        let id = job.id.clone();
+        in_flight.lock().unwrap().push(job);
+        InFlightGuard { in_flight, id }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .retain(|job| job.id != self.id);
+    }
+}
+
+/// Writes the in-flight jobs of a worker to its heartbeat hash every third of the ttl
+/// until it is dropped, the hash of a dead worker expires after the ttl
+struct Heartbeat {
+    done: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    /// start beating for [worker], `None` if [ttl] is 0
+    fn start(queue: Queue, worker: String, in_flight: InFlight, ttl: u32) -> Option<Self> {
+        if ttl == 0 {
+            return None;
+        }
+        let done = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&done);
+        let interval = Duration::from_millis(ttl as u64 * 1000 / 3);
+        let thread = thread::spawn(move || {
+            let mut next = Instant::now();
+            while !stop.load(Ordering::SeqCst) {
+                if Instant::now() >= next {
+                    let jobs: Vec<(JobId, u64)> = in_flight
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|job| (job.id.clone(), job.started_at))
+                        .collect();
+                    if let Err(e) = queue.beat(&worker, &jobs, ttl) {
+                        warn!("Heartbeat of worker [{}] failed: {}", worker, e);
+                    }
+                    next = Instant::now() + interval;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            if let Err(e) = queue.forget_worker(&worker) {
+                warn!("Heartbeat of worker [{}] not removed: {}", worker, e);
+            }
+        });
+        Some(Heartbeat {
+            done,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// the default id of a worker, the host name, process id and a counter of the process
+fn default_worker_id() -> String {
+    static WORKERS: AtomicU32 = AtomicU32::new(0);
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "worker".to_string());
+    format!(
+        "{}-{}-{}",
+        host,
+        std::process::id(),
+        WORKERS.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+type StartHook = Arc<dyn Fn(&mut JobContext) -> QResult<()> + Send + Sync>;
+type StopHook = Arc<dyn Fn(&JobContext) + Send + Sync>;
+
+/// The hooks run once per worker thread around its loop
+#[derive(Clone, Default)]
+struct Hooks {
+    on_start: Option<StartHook>,
+    on_stop: Option<StopHook>,
+}
+
+impl Hooks {
+    /// build the context of a worker thread, an error stops the worker before any job
+    fn start(&self, state: &AppState) -> QResult<JobContext> {
+        let mut ctx = JobContext::new(state.clone());
+        if let Some(on_start) = &self.on_start {
+            on_start(&mut ctx)?;
+        }
+        Ok(ctx)
+    }
+    fn stop(&self, ctx: &JobContext) {
+        if let Some(on_stop) = &self.on_stop {
+            on_stop(ctx);
+        }
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_start", &self.on_start.is_some())
+            .field("on_stop", &self.on_stop.is_some())
+            .finish()
+    }
+}
+
+/// A worker executing the jobs of a queue, a redis `Queue` by default or any other
+/// `QueueBackend`
+#[derive(Debug)]
+pub struct QueueTask<B: QueueBackend = Queue> {
+    pub inner: Arc<Mutex<B>>,
+    in_flight: InFlight,
+    /// seconds between reloads of the channel config while listening, 0 disables reloading
+    config_interval: u64,
+    /// how long a listening worker sleeps when no job was fetched or an error occurred
+    poll_interval: Duration,
+    /// seconds `reserve` blocks waiting for a job, 0 polls without blocking
+    block_timeout: u64,
+    /// what happens to jobs whose payload does not deserialize
+    decode_failure: DecodeFailure,
+    /// whether the worker shows its state in the process title
+    process_title: bool,
+    /// the name the worker beats under
+    worker_id: String,
+    /// seconds the heartbeat of the worker lives without a refresh, 0 writes none
+    heartbeat: u32,
+    /// set by `stop` to end `run` and `listen`
+    stopping: Arc<AtomicBool>,
+    hooks: Hooks,
+    /// values shared with the jobs of every worker thread
+    state: AppState,
+    /// the handlers of jobs pushed with `Queue::push_fn`
+    fn_handlers: FnHandlers,
+    /// the handlers of jobs without a rust type by their type name
+    type_handlers: FnHandlers,
+    /// posts `WorkerDied` when a worker stops on an error
+    #[cfg(feature = "webhook")]
+    webhook: Option<Arc<Webhook>>,
+}
+impl<B: QueueBackend + 'static> QueueTask<B> {
+    /// init a worker of [queue]
+    pub fn new(queue: B) -> Self {
         QueueTask {
             inner: Arc::new(Mutex::new(queue)),
+            in_flight: Arc::new(Mutex::new(Vec::new())),
+            config_interval: 5,
+            poll_interval: Duration::from_millis(1000),
+            block_timeout: 0,
+            decode_failure: DecodeFailure::Park,
+            process_title: false,
+            worker_id: default_worker_id(),
+            heartbeat: 0,
+            stopping: Arc::new(AtomicBool::new(false)),
+            hooks: Hooks::default(),
+            state: AppState::default(),
+            fn_handlers: FnHandlers::default(),
+            type_handlers: FnHandlers::default(),
+            #[cfg(feature = "webhook")]
+            webhook: None,
         }
     }
-    /// run all jobs in queue, loop until an error occur
-    pub fn run(&self, timeout: u64) -> Result<(), QError> {
+    /// register the handler of jobs whose `type` is [job_type] and that have no `JobTrait`
+    /// implementation, such as jobs pushed by other languages or `Queue::push_raw`.
+    /// It receives the whole json object of the job and the job context
+    pub fn register_handler<F>(&mut self, job_type: impl Into<String>, handler: F) -> &mut Self
+    where
+        F: Fn(serde_json::Value, &JobContext) -> QResult<()> + Send + Sync + 'static,
+    {
+        self.type_handlers.insert(job_type, handler);
+        self
+    }
+    /// register the handler of jobs pushed with `Queue::push_fn(name, args)`,
+    /// it receives the serialized args and the job context
+    pub fn register_fn<F>(&mut self, name: impl Into<String>, handler: F) -> &mut Self
+    where
+        F: Fn(serde_json::Value, &JobContext) -> QResult<()> + Send + Sync + 'static,
+    {
+        self.fn_handlers.insert(name, handler);
+        self
+    }
+    /// the app state handed to the worker threads
+    fn worker_state(&self) -> AppState {
+        let mut state = self.state.clone();
+        if let Some(dispatcher) = self.inner.lock().unwrap().dispatcher() {
+            state.insert(dispatcher);
+        }
+        if !self.fn_handlers.is_empty() {
+            state.insert(self.fn_handlers.clone());
+        }
+        if !self.type_handlers.is_empty() {
+            state.insert(TypeHandlers(self.type_handlers.clone()));
+        }
+        state
+    }
+    /// register a value shared by every job this task executes, such as a database pool,
+    /// jobs read it with `JobContext::get` in `execute_with`
+    pub fn state<T: Any + Send + Sync>(&mut self, value: T) -> &mut Self {
+        self.state.insert(value);
+        self
+    }
+    /// set a hook run once when a worker thread starts, before it reserves any job,
+    /// to put shared state such as connection pools into the context jobs execute with.
+    /// An error stops the worker
+    pub fn on_start<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&mut JobContext) -> QResult<()> + Send + Sync + 'static,
+    {
+        self.hooks.on_start = Some(Arc::new(hook));
+        self
+    }
+    /// set a hook run once when a worker thread stops, to release the state of `on_start`
+    pub fn on_stop<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&JobContext) + Send + Sync + 'static,
+    {
+        self.hooks.on_stop = Some(Arc::new(hook));
+        self
+    }
+    /// stop `run` or `listen` after the current job, a stopped task stays stopped
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+    /// set how long a listening worker sleeps when no job was fetched or an error occurred,
+    /// shorter intervals lower the latency, longer ones the load on redis
+    pub fn poll_interval(&mut self, interval: Duration) -> &mut Self {
+        self.poll_interval = interval;
+        self
+    }
+    /// set the seconds `reserve` blocks on the waiting list (BRPOP) before giving up,
+    /// 0 polls without blocking
+    pub fn block_timeout(&mut self, seconds: u64) -> &mut Self {
+        self.block_timeout = seconds;
+        self
+    }
+    /// set what happens to jobs whose payload does not deserialize, instead of stopping `run`
+    pub fn decode_failure(&mut self, policy: DecodeFailure) -> &mut Self {
+        self.decode_failure = policy;
+        self
+    }
+    /// show the state of the worker as the title of its process, such as
+    /// `queue-rs: emails [1 busy/1] last=SendEmail`, printed by `ps` and `top` on Linux only.
+    /// The title overwrites the command line, so it is cut to the length of the original
+    /// command line and `std::env::args` no longer returns the arguments. It changes at most
+    /// once per second
+    pub fn process_title(&mut self, enabled: bool) -> &mut Self {
+        self.process_title = enabled;
+        self
+    }
+    /// write the jobs this worker executes with their start times to a hash of the channel,
+    /// read with `Queue::workers`, refreshed every third of [ttl] seconds so the hash of a
+    /// dead worker expires after [ttl], 0 writes none. Only a backend with a dispatcher such
+    /// as `Queue` keeps heartbeats
+    pub fn heartbeat(&mut self, ttl: u32) -> &mut Self {
+        self.heartbeat = ttl;
+        self
+    }
+    /// set the name the worker beats under, the host name, process id and a counter by default
+    pub fn worker_id(&mut self, id: impl Into<String>) -> &mut Self {
+        self.worker_id = id.into();
+        self
+    }
+    /// set the seconds between reloads of the channel config while listening,
+    /// so pause state, defaults and rate limits changed in redis apply without a restart
+    pub fn config_interval(&mut self, seconds: u64) -> &mut Self {
+        self.config_interval = seconds;
+        self
+    }
+    /// beat for this worker when `heartbeat` is set
+    fn start_heartbeat(&self) -> Option<Heartbeat> {
+        let queue = self.inner.lock().unwrap().dispatcher()?.queue().clone();
+        let in_flight = Arc::clone(&self.in_flight);
+        Heartbeat::start(queue, self.worker_id.clone(), in_flight, self.heartbeat)
+    }
+    /// the jobs this worker is executing right now with their start times
+    pub fn in_flight(&self) -> Vec<InFlightJob> {
+        self.in_flight.lock().unwrap().clone()
+    }
+    /// run all jobs in queue, loop until no job is left or an error occur
+    pub fn run(&self) -> WorkerReport {
         let inner = Arc::clone(&self.inner);
-        thread::spawn(move || -> QResult<()> {
-            loop {
+        let in_flight = Arc::clone(&self.in_flight);
+        let stopping = Arc::clone(&self.stopping);
+        let timeout = self.block_timeout;
+        let decode_failure = self.decode_failure;
+        let mut title = self
+            .process_title
+            .then(|| ProcessTitle::new(self.inner.lock().unwrap().name()));
+        let hooks = self.hooks.clone();
+        let state = self.worker_state();
+        let heartbeat = self.start_heartbeat();
+        let report = thread::spawn(move || {
+            let started = Instant::now();
+            let mut report = WorkerReport::start();
+            let ctx = match hooks.start(&state) {
+                Ok(ctx) => ctx,
+                Err(e) => return report.finish(started, ExitCause::Error(e)),
+            };
+            let cause = loop {
+                if stopping.load(Ordering::SeqCst) {
+                    break ExitCause::Stopped;
+                }
                 let inner = inner.lock().unwrap();
-                let job = inner.reserve(timeout)?;
-                let message_id = job.0;
-                inner.handle_message(job)?;
-                inner.delete(message_id)?;
-            }
+                let job = match inner.reserve(timeout) {
+                    Ok(job) => job,
+                    Err(e) if e.kind() == ErrorKind::NotFound => break ExitCause::Drained,
+                    Err(e) => break ExitCause::Error(e),
+                };
+                let guard = InFlightGuard::new(&in_flight, &job);
+                if let Some(title) = &mut title {
+                    title.started(&job);
+                    title.show(1, 1);
+                }
+                let result = inner.handle_message_with(&job, &ctx);
+                drop(guard);
+                if let Some(title) = &mut title {
+                    title.show(0, 1);
+                }
+                match result {
+                    // the job was released until its rate limit bucket refills or its
+                    // concurrency key is free
+                    Err(e) if matches!(e.kind(), ErrorKind::RateLimited | ErrorKind::Locked) => {
+                        continue
+                    }
+                    Err(e) if e.kind() == ErrorKind::JsonConvert => {
+                        report.processed += 1;
+                        report.failed += 1;
+                        if let Err(e) = undecodable(&*inner, &job, decode_failure, e) {
+                            break ExitCause::Error(e);
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        report.processed += 1;
+                        report.failed += 1;
+                        break ExitCause::Error(e);
+                    }
+                    Ok(outcome) => {
+                        report.processed += 1;
+                        if outcome.is_failure() {
+                            report.failed += 1;
+                        }
+                        if let Err(e) = inner.settle(&job, &outcome) {
+                            break ExitCause::Error(e);
+                        }
+                        // the failure may have buried the job past the bury limit
+                        if outcome.is_failure() {
+                            match inner.config() {
+                                Ok(config) if config.paused.unwrap_or(false) => {
+                                    break ExitCause::Paused
+                                }
+                                Ok(_) => {}
+                                Err(e) => break ExitCause::Error(e),
+                            }
+                        }
+                    }
+                }
+            };
+            hooks.stop(&ctx);
+            report.finish(started, cause)
         })
         .join()
-        .unwrap()?;
-        Ok(())
+        .unwrap();
+        drop(heartbeat);
+        #[cfg(feature = "webhook")]
+        self.report_exit(&report);
+        report
+    }
+    /// post a `WorkerDied` webhook when `run` or `listen` stops on an error
+    #[cfg(feature = "webhook")]
+    pub fn webhook(&mut self, webhook: Webhook) -> &mut Self {
+        self.webhook = Some(Arc::new(webhook));
+        self
+    }
+    /// post the error a worker stopped on
+    #[cfg(feature = "webhook")]
+    fn report_exit(&self, report: &WorkerReport) {
+        let (Some(webhook), ExitCause::Error(e)) = (&self.webhook, &report.cause) else {
+            return;
+        };
+        let event = WebhookEvent::WorkerDied {
+            channel: self.inner.lock().unwrap().name().to_string(),
+            error: e.to_string(),
+        };
+        if let Err(e) = webhook.send(&event) {
+            error!("{}", e);
+        }
     }
-    /// run a task to fetch all jobs and execute them
-    /// timeout: the timeout of the job
-    /// todo : the error msg should write to log file, not print to stdout now because of loop without a break
-    pub fn listen(&self, timeout: u64) {
+    /// run a task to fetch all jobs and execute them, errors are logged and the loop goes on
+    /// until `stop` is called
+    pub fn listen(&self) -> WorkerReport {
         let inner = Arc::clone(&self.inner);
+        let in_flight = Arc::clone(&self.in_flight);
+        let stopping = Arc::clone(&self.stopping);
+        let timeout = self.block_timeout;
+        let decode_failure = self.decode_failure;
+        let mut title = self
+            .process_title
+            .then(|| ProcessTitle::new(self.inner.lock().unwrap().name()));
+        let poll_interval = self.poll_interval;
+        let config_interval = Duration::from_secs(self.config_interval);
+        let mut reloaded_at: Option<Instant> = None;
+        let mut paused = false;
+        let hooks = self.hooks.clone();
+        let state = self.worker_state();
+        let heartbeat = self.start_heartbeat();
 
-        let _ = thread::spawn(move || loop {
-            let inner = inner.lock().unwrap();
-            let job = inner.reserve(timeout);
-            match job {
-                Ok(job) => {
-                    let message_id = job.0;
-                    let result = inner.handle_message(job);
-                    if result.is_err() {
-                        thread::sleep(Duration::from_millis(1000));
-                        continue;
-                    }
-                    let result = inner.delete(message_id);
-                    if result.is_err() {
-                        thread::sleep(Duration::from_millis(1000));
-                        continue;
+        let report = thread::spawn(move || {
+            let started = Instant::now();
+            let mut report = WorkerReport::start();
+            let ctx = match hooks.start(&state) {
+                Ok(ctx) => ctx,
+                Err(e) => return report.finish(started, ExitCause::Error(e)),
+            };
+            while !stopping.load(Ordering::SeqCst) {
+                let mut inner = inner.lock().unwrap();
+                if !config_interval.is_zero()
+                    && reloaded_at.is_none_or(|at| at.elapsed() >= config_interval)
+                {
+                    match inner.config() {
+                        Ok(config) => {
+                            if config.paused.unwrap_or(false) != paused {
+                                paused = !paused;
+                                info!("Channel paused state changed to [{}]", paused);
+                            }
+                            inner.apply_config(&config);
+                        }
+                        Err(e) => error!("{}", e),
                     }
+                    reloaded_at = Some(Instant::now());
                 }
-                Err(e) => {
-                    println!("{:?}", e);
-                    thread::sleep(Duration::from_millis(1000));
+                if paused {
+                    drop(inner);
+                    thread::sleep(poll_interval);
                     continue;
                 }
-            };
-            thread::sleep(Duration::from_millis(1000));
+                let job = inner.reserve(timeout);
+                let result = match job {
+                    Ok(job) => {
+                        let guard = InFlightGuard::new(&in_flight, &job);
+                        if let Some(title) = &mut title {
+                            title.started(&job);
+                            title.show(1, 1);
+                        }
+                        let result = inner.handle_message_with(&job, &ctx);
+                        drop(guard);
+                        if let Some(title) = &mut title {
+                            title.show(0, 1);
+                        }
+                        match &result {
+                            Err(e)
+                                if matches!(
+                                    e.kind(),
+                                    ErrorKind::RateLimited | ErrorKind::Locked
+                                ) => {}
+                            Err(_) => {
+                                report.processed += 1;
+                                report.failed += 1;
+                            }
+                            Ok(outcome) => {
+                                report.processed += 1;
+                                if outcome.is_failure() {
+                                    report.failed += 1;
+                                    // reload the paused state the bury limit may have set
+                                    reloaded_at = None;
+                                }
+                            }
+                        }
+                        match result {
+                            Err(e) if e.kind() == ErrorKind::JsonConvert => {
+                                undecodable(&*inner, &job, decode_failure, e)
+                            }
+                            result => {
+                                result.and_then(|outcome| inner.settle(&job, &outcome).map(|_| ()))
+                            }
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::NotFound => {
+                        // shows the worker idle if the last change was skipped
+                        if let Some(title) = &mut title {
+                            title.show(0, 1);
+                        }
+                        // the blocking pop already waited for a job
+                        if timeout > 0 {
+                            continue;
+                        }
+                        debug!("{}", e);
+                        Err(e)
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        Err(e)
+                    }
+                };
+                if result.is_err() {
+                    drop(inner);
+                    thread::sleep(poll_interval);
+                }
+            }
+            hooks.stop(&ctx);
+            report.finish(started, ExitCause::Stopped)
         })
         .join()
         .unwrap();
+        drop(heartbeat);
+        #[cfg(feature = "webhook")]
+        self.report_exit(&report);
+        report
+    }
+}
+
+impl QueueTask<Queue> {
+    /// set how much the worker logs about each job, e.g. `Verbosity::quiet()` for busy channels
+    pub fn verbosity(&mut self, verbosity: Verbosity) -> &mut Self {
+        self.inner.lock().unwrap().verbosity(verbosity);
+        self
     }
 }
 
 // test
 #[cfg(test)]
 mod tests {
+    use crate::job::JobTrait;
+    use crate::{err, QResult};
+    use serde::{Deserialize, Serialize};
     use tracing_subscriber;
 
+    #[derive(Serialize, Deserialize)]
+    struct FailingJob {}
+    #[typetag::serde]
+    impl JobTrait for FailingJob {
+        fn execute(&self) -> QResult<()> {
+            err!("downstream unavailable")
+        }
+    }
+
     // test listen should work
     #[test]
     fn test_run() {
@@ -80,7 +775,307 @@ mod tests {
 
         let queue = Queue::new("test", redis::Client::open("redis://127.0.0.1/").unwrap());
         let task = QueueTask::new(queue);
-        let _ = task.run(0);
+        let _ = task.run();
+    }
+    // test a stopped task returns a report
+    #[test]
+    fn test_stop_report() {
+        use super::{ExitCause, QueueTask};
+        use crate::queue::Queue;
+
+        let queue = Queue::new("test", redis::Client::open("redis://127.0.0.1/").unwrap());
+        let task = QueueTask::new(queue);
+        task.stop();
+        let report = task.run();
+        assert!(matches!(report.cause, ExitCause::Stopped));
+        assert_eq!(report.processed, 0);
+        let report = task.listen();
+        assert!(matches!(report.cause, ExitCause::Stopped));
+    }
+    // test start and stop hooks run around the worker loop
+    #[test]
+    fn test_hooks() {
+        use super::{ExitCause, QueueTask};
+        use crate::queue::Queue;
+        use crate::{err, QResult};
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+
+        let queue = Queue::new("test", redis::Client::open("redis://127.0.0.1/").unwrap());
+        let mut task = QueueTask::new(queue);
+        let stopped = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&stopped);
+        task.state(40u32)
+            .on_start(|ctx| {
+                let base = *ctx.get::<u32>().unwrap();
+                ctx.insert(base + 2);
+                Ok(())
+            })
+            .on_stop(move |ctx| {
+                counter.store(*ctx.get::<u32>().unwrap(), Ordering::SeqCst);
+            });
+        task.stop();
+        task.run();
+        assert_eq!(stopped.load(Ordering::SeqCst), 42);
+        let ctx = task.hooks.start(&task.worker_state()).unwrap();
+        assert!(ctx.dispatcher().is_some());
+        task.on_start(|_| -> QResult<()> { err!("no database") });
+        let report = task.run();
+        assert!(matches!(report.cause, ExitCause::Error(_)));
+    }
+    // test in flight jobs are tracked while executing
+    #[test]
+    fn test_in_flight() {
+        use super::{InFlightGuard, QueueTask};
+        use crate::id::JobId;
+        use crate::queue::{Queue, ReservedJob};
+
+        let queue = Queue::new("test", redis::Client::open("redis://127.0.0.1/").unwrap());
+        let task = QueueTask::new(queue);
+        let job = ReservedJob {
+            id: JobId::from(7),
+            message: "{}".to_string(),
+            ttr: 300,
+            attempts: 1,
+            token: "token".to_string(),
+            metadata: Default::default(),
+        };
+        let guard = InFlightGuard::new(&task.in_flight, &job);
+        let in_flight = task.in_flight();
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(in_flight[0].id, JobId::from(7));
+        drop(guard);
+        assert!(task.in_flight().is_empty());
+    }
+    // test the heartbeat lists the in flight jobs of a worker until it stops
+    #[test]
+    fn test_heartbeat() {
+        use super::{InFlightGuard, QueueTask};
+        use crate::id::JobId;
+        use crate::queue::{Queue, ReservedJob};
+        use std::thread;
+        use std::time::Duration;
+
+        let queue = Queue::new(
+            "test-heartbeat",
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+        );
+        queue.clear().unwrap();
+        let mut task = QueueTask::new(queue.clone());
+        task.heartbeat(3).worker_id("worker-7");
+        let job = ReservedJob {
+            id: JobId::from(7),
+            message: "{}".to_string(),
+            ttr: 300,
+            attempts: 1,
+            token: "token".to_string(),
+            metadata: Default::default(),
+        };
+        let guard = InFlightGuard::new(&task.in_flight, &job);
+        let heartbeat = task.start_heartbeat();
+        thread::sleep(Duration::from_millis(300));
+        let workers = queue.workers().unwrap();
+        assert_eq!(workers.len(), 1);
+        assert_eq!(workers[0].worker, "worker-7");
+        assert!(workers[0].in_flight.contains_key(&JobId::from(7)));
+        drop(guard);
+        drop(heartbeat);
+        assert!(queue.workers().unwrap().is_empty());
+    }
+    // test workers of one process get distinct ids
+    #[test]
+    fn test_worker_id() {
+        use super::default_worker_id;
+        assert_ne!(default_worker_id(), default_worker_id());
+    }
+    // test the process title shows the worker state within the command line
+    #[test]
+    fn test_process_title() {
+        use super::ProcessTitle;
+        use crate::id::JobId;
+        use crate::queue::ReservedJob;
+        let mut title = ProcessTitle::new("emails");
+        assert_eq!(title.title(0, 5), "queue-rs: emails [0 busy/5]");
+        title.started(&ReservedJob {
+            id: JobId::from(1),
+            message: "{\"type\":\"SendEmail\"}".to_string(),
+            ttr: 60,
+            attempts: 1,
+            token: String::new(),
+            metadata: Default::default(),
+        });
+        assert_eq!(
+            title.title(3, 5),
+            "queue-rs: emails [3 busy/5] last=SendEmail"
+        );
+        #[cfg(target_os = "linux")]
+        {
+            use super::{cut, set_process_title};
+            assert_eq!(cut("emails", 3), "ema");
+            assert_eq!(cut("é".repeat(10).as_str(), 15).len(), 14);
+            assert_eq!(cut("emails", 10), "emails");
+            let length = std::fs::read("/proc/self/cmdline").unwrap().len();
+            set_process_title("queue-rs: emails [1 busy/1]").unwrap();
+            let cmdline = std::fs::read("/proc/self/cmdline").unwrap();
+            assert_eq!(cmdline.len(), length);
+            // the path of the test binary is longer than `queue-rs`
+            assert!(cmdline.starts_with(b"queue-rs"));
+        }
+    }
+    // test a worker runs the jobs of a backend other than redis
+    #[test]
+    fn test_memory_backend() {
+        use super::{ExitCause, QueueTask};
+        use crate::backend::{MemoryQueue, QueueBackend};
+        use crate::queue::STATUS_DONE;
+
+        let queue = MemoryQueue::new(60);
+        let id = queue.push(FailingJob {}).unwrap();
+        let task = QueueTask::new(queue);
+        let report = task.run();
+        assert!(matches!(report.cause, ExitCause::Drained));
+        assert_eq!((report.processed, report.failed), (1, 1));
+        assert_eq!(task.inner.lock().unwrap().status(&id).unwrap(), STATUS_DONE);
+        let ctx = task.hooks.start(&task.worker_state()).unwrap();
+        assert!(ctx.dispatcher().is_none());
+    }
+    // test a listening worker applies the changed channel config without a restart
+    #[test]
+    fn test_reload_config() {
+        use super::QueueTask;
+        use crate::backend::{MemoryQueue, QueueBackend};
+        use crate::config::ChannelConfig;
+        use crate::id::JobId;
+        use crate::queue::ReservedJob;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        /// a memory queue with a channel config
+        #[derive(Debug, Default)]
+        struct Configured {
+            jobs: MemoryQueue,
+            config: ChannelConfig,
+            applied: Option<ChannelConfig>,
+        }
+        impl QueueBackend for Configured {
+            fn push_message(&self, message: String) -> QResult<JobId> {
+                self.jobs.push_message(message)
+            }
+            fn reserve(&self, timeout: u64) -> QResult<ReservedJob> {
+                self.jobs.reserve(timeout)
+            }
+            fn delete(&self, id: &JobId, token: &str) -> QResult<bool> {
+                self.jobs.delete(id, token)
+            }
+            fn move_expired(&self) -> QResult<()> {
+                self.jobs.move_expired()
+            }
+            fn status(&self, id: &JobId) -> QResult<u8> {
+                self.jobs.status(id)
+            }
+            fn clear(&self) -> QResult<()> {
+                self.jobs.clear()
+            }
+            fn config(&self) -> QResult<ChannelConfig> {
+                Ok(self.config.clone())
+            }
+            fn apply_config(&mut self, config: &ChannelConfig) {
+                self.applied = Some(config.clone());
+            }
+        }
+        let applied = |task: &QueueTask<Configured>| {
+            let rate = task
+                .inner
+                .lock()
+                .unwrap()
+                .applied
+                .as_ref()?
+                .rate_limits
+                .get("smtp")?
+                .to_string();
+            Some(rate)
+        };
+        let mut task = QueueTask::new(Configured::default());
+        task.inner
+            .lock()
+            .unwrap()
+            .config
+            .rate_limits
+            .insert("smtp".to_string(), "10/60".parse().unwrap());
+        task.config_interval(1)
+            .poll_interval(Duration::from_millis(20));
+        thread::scope(|s| {
+            let worker = s.spawn(|| task.listen());
+            let wait_for = |rate: &str| {
+                let deadline = Instant::now() + Duration::from_secs(3);
+                while applied(&task).as_deref() != Some(rate) {
+                    assert!(Instant::now() < deadline, "rate limit {} not applied", rate);
+                    thread::sleep(Duration::from_millis(20));
+                }
+            };
+            wait_for("10/60");
+            task.inner
+                .lock()
+                .unwrap()
+                .config
+                .rate_limits
+                .insert("smtp".to_string(), "5/60".parse().unwrap());
+            wait_for("5/60");
+            task.stop();
+            worker.join().unwrap();
+        });
+    }
+    // test a job failing on each of its attempts ends in the dead letter channel
+    #[test]
+    fn test_dead_letter_attempts() {
+        use super::{ExitCause, QueueTask};
+        use crate::queue::{Queue, STATUS_DONE};
+
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let mut queue = Queue::new("test-dead-letter-attempts", client.clone());
+        let dlq = Queue::new("test-dead-letter-attempts.dlq", client);
+        queue.clear().unwrap();
+        dlq.clear().unwrap();
+        queue
+            .attempts(3)
+            .retry_backoff(0, 0)
+            .retry_failed(true)
+            .dead_letter("test-dead-letter-attempts.dlq");
+        let id = queue.push(FailingJob {}).unwrap();
+        let task = QueueTask::new(queue.clone());
+        let report = task.run();
+        assert!(matches!(report.cause, ExitCause::Drained));
+        assert_eq!(report.processed, 3);
+        assert_eq!(report.failed, 3);
+        assert_eq!(queue.status(&id).unwrap(), STATUS_DONE);
+        let job = dlq.reserve(0).unwrap();
+        assert_eq!(
+            job.metadata.get("dead_from").map(String::as_str),
+            Some(format!("test-dead-letter-attempts/{}", id).as_str())
+        );
+        queue.clear().unwrap();
+        dlq.clear().unwrap();
+    }
+    // test a burst of failing jobs pauses the channel through the bury limit
+    #[test]
+    fn test_bury_storm() {
+        use super::{ExitCause, QueueTask};
+        use crate::queue::{Queue, STATUS_BURIED, STATUS_WAITING};
+
+        let mut queue = Queue::new(
+            "test-bury-storm",
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+        );
+        queue.clear().unwrap();
+        queue.bury_limit(2, 60).retry_failed(true);
+        let ids: Vec<_> = (0..5).map(|_| queue.push(FailingJob {}).unwrap()).collect();
+        let report = QueueTask::new(queue.clone()).run();
+        assert!(matches!(report.cause, ExitCause::Paused));
+        assert_eq!(report.failed, 3);
+        assert_eq!(queue.config().unwrap().paused, Some(true));
+        assert_eq!(queue.status(&ids[0]).unwrap(), STATUS_BURIED);
+        assert_eq!(queue.status(&ids[4]).unwrap(), STATUS_WAITING);
+        queue.clear().unwrap();
     }
     // test run should work
     #[test]
@@ -89,7 +1084,8 @@ mod tests {
         use crate::queue::Queue;
         tracing_subscriber::fmt::init();
         let queue = Queue::new("test", redis::Client::open("redis://127.0.0.1/").unwrap());
-        let task = QueueTask::new(queue);
-        let _ = task.listen(1);
+        let mut task = QueueTask::new(queue);
+        task.block_timeout(1);
+        task.listen();
     }
 }
@@ -0,0 +1,306 @@
+use crate::backend::QueueBackend;
+use crate::error::ErrorKind;
+use crate::id::JobId;
+use crate::queue::{Queue, ReservedJob, STATUS_DONE, STATUS_RESERVED};
+use crate::{err, timestamp, QResult};
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+/// A queue with a fake clock for tests, `advance` moves the clock and promotes the delayed
+/// jobs that became due, so a test can check that a reminder runs after ten minutes
+/// without sleeping. The fake clock only runs ahead of the real one
+#[derive(Debug)]
+pub struct TestQueue {
+    queue: Queue,
+    /// the fake unix time
+    now: u64,
+}
+
+impl TestQueue {
+    pub fn new(queue: Queue) -> QResult<Self> {
+        Ok(TestQueue {
+            queue,
+            now: timestamp()?,
+        })
+    }
+    /// the queue to push to and inspect
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+    /// the fake unix time
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+    /// move the fake clock forward by [by] and move the delayed jobs due by then to the
+    /// waiting list, return the number of promoted jobs
+    pub fn advance(&mut self, by: Duration) -> QResult<usize> {
+        self.now += by.as_secs();
+        self.queue.promote_until(self.now)
+    }
+    /// execute the waiting jobs until none is left, failed ones are settled like on a
+    /// worker, return the number of executed jobs
+    pub fn run_waiting(&self) -> QResult<usize> {
+        let mut executed = 0;
+        loop {
+            let job = match self.queue.reserve(0) {
+                Ok(job) => job,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(executed),
+                Err(e) => return Err(e),
+            };
+            let outcome = self.queue.handle_message(&job)?;
+            self.queue.settle(&job, &outcome)?;
+            executed += 1;
+        }
+    }
+}
+
+type Scenario = fn(&dyn QueueBackend) -> QResult<()>;
+
+/// Check that a `QueueBackend` keeps the at-least-once delivery contract the workers rely
+/// on, with the crash and redelivery scenarios the crate checks its own backends with.
+/// [backend] makes an empty backend whose reservations last the given ttr in seconds,
+/// the check takes a few seconds as it waits for reservations to expire
+pub fn verify_delivery<B: QueueBackend>(backend: impl Fn(u32) -> B) -> QResult<()> {
+    let scenarios: [(&str, Scenario); 4] = [
+        ("deliver once", deliver_once),
+        ("worker killed mid job", worker_killed),
+        ("stale reservation", stale_reservation),
+        ("no duplicate delivery", no_duplicate),
+    ];
+    for (name, scenario) in scenarios {
+        let backend = backend(1);
+        backend.clear()?;
+        let result = scenario(&backend);
+        backend.clear()?;
+        if let Err(e) = result {
+            return err!(
+                ErrorKind::Other,
+                "delivery scenario [{}] failed: {}",
+                name,
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// reserve the next job, failing if there is none
+fn reserve(backend: &dyn QueueBackend) -> QResult<ReservedJob> {
+    match backend.reserve(0) {
+        Err(e) if e.kind() == ErrorKind::NotFound => err!("no job delivered"),
+        result => result,
+    }
+}
+
+/// wait past the ttr of 1s and move the expired reservations back
+fn expire(backend: &dyn QueueBackend) -> QResult<()> {
+    thread::sleep(Duration::from_millis(2100));
+    backend.move_expired()
+}
+
+/// a pushed job is delivered with its message and done once deleted
+fn deliver_once(backend: &dyn QueueBackend) -> QResult<()> {
+    let message = "{\"type\":\"ContractJob\",\"n\":1}";
+    let id = backend.push_message(message.to_string())?;
+    let job = reserve(backend)?;
+    if job.id != id || job.message != message || job.attempts != 1 {
+        return err!("delivered {:?} for job id:[{}]", job, id);
+    }
+    if backend.status(&id)? != STATUS_RESERVED {
+        return err!("a delivered job is not reserved");
+    }
+    if !backend.delete(&id, &job.token)? {
+        return err!("the holder of the reservation can not delete the job");
+    }
+    if backend.status(&id)? != STATUS_DONE {
+        return err!("a deleted job is not done");
+    }
+    if backend.reserve(0).is_ok() {
+        return err!("a deleted job was delivered again");
+    }
+    Ok(())
+}
+
+/// a job whose worker died before deleting it is delivered again once its ttr expired
+fn worker_killed(backend: &dyn QueueBackend) -> QResult<()> {
+    let id = backend.push_message("{\"type\":\"ContractJob\",\"n\":2}".to_string())?;
+    let dead = thread::scope(|scope| scope.spawn(|| reserve(backend)).join())
+        .unwrap_or_else(|_| err!("the worker thread panicked"))?;
+    expire(backend)?;
+    let job = reserve(backend)?;
+    if job.id != id || job.attempts != 2 {
+        return err!(
+            "delivered job id:[{}] attempt [{}] after job id:[{}] expired",
+            job.id,
+            job.attempts,
+            dead.id
+        );
+    }
+    backend.delete(&job.id, &job.token)?;
+    Ok(())
+}
+
+/// the worker of an expired reservation can not delete the job redelivered to another
+fn stale_reservation(backend: &dyn QueueBackend) -> QResult<()> {
+    backend.push_message("{\"type\":\"ContractJob\",\"n\":3}".to_string())?;
+    let stale = reserve(backend)?;
+    expire(backend)?;
+    let job = reserve(backend)?;
+    if backend.delete(&stale.id, &stale.token)? {
+        return err!("a stale reservation deleted the job");
+    }
+    if !backend.delete(&job.id, &job.token)? {
+        return err!("the current reservation can not delete the job");
+    }
+    Ok(())
+}
+
+/// jobs reserved by concurrent workers are each delivered to one worker only
+fn no_duplicate(backend: &dyn QueueBackend) -> QResult<()> {
+    let pushed: HashSet<JobId> = (0..20)
+        .map(|n| backend.push_message(format!("{{\"type\":\"ContractJob\",\"n\":{}}}", n)))
+        .collect::<QResult<_>>()?;
+    let delivered: Vec<Vec<JobId>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut ids = Vec::new();
+                    while let Ok(job) = backend.reserve(0) {
+                        ids.push(job.id);
+                    }
+                    ids
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap_or_default())
+            .collect()
+    });
+    let delivered: Vec<JobId> = delivered.into_iter().flatten().collect();
+    let unique: HashSet<JobId> = delivered.iter().cloned().collect();
+    if delivered.len() != unique.len() {
+        return err!("{} deliveries for {} jobs", delivered.len(), unique.len());
+    }
+    if unique != pushed {
+        return err!("{} of {} jobs delivered", unique.len(), pushed.len());
+    }
+    Ok(())
+}
+
+// test fake clock
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::JobTrait;
+    use crate::queue::STATUS_WAITING;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct ReminderJob {
+        user: u32,
+    }
+    #[typetag::serde]
+    impl JobTrait for ReminderJob {
+        fn execute(&self) -> QResult<()> {
+            println!("reminded user [{}]", self.user);
+            Ok(())
+        }
+    }
+
+    // test the crate backends keep the delivery contract
+    #[test]
+    fn test_delivery_contract() {
+        use crate::backend::{FileQueue, MemoryQueue};
+        verify_delivery(MemoryQueue::new).unwrap();
+        let path = std::env::temp_dir().join(format!("queue-rs-contract-{}", std::process::id()));
+        verify_delivery(|ttr| FileQueue::open(&path, ttr).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+    // test redis queues keep the delivery contract
+    #[test]
+    fn test_redis_delivery_contract() {
+        verify_delivery(|ttr| {
+            let mut queue = Queue::new(
+                "test-contract",
+                redis::Client::open("redis://127.0.0.1/").unwrap(),
+            );
+            queue.ttl(ttr);
+            queue
+        })
+        .unwrap();
+    }
+    // test beanstalkd tubes keep the delivery contract
+    #[test]
+    fn test_beanstalk_delivery_contract() {
+        use crate::beanstalk::BeanstalkQueue;
+        verify_delivery(|ttr| BeanstalkQueue::new("127.0.0.1:11300", "test-contract", ttr))
+            .unwrap();
+    }
+    // test AMQP queues keep the delivery contract
+    #[cfg(feature = "amqp")]
+    #[test]
+    fn test_amqp_delivery_contract() {
+        use crate::amqp::AmqpQueue;
+        verify_delivery(|ttr| {
+            AmqpQueue::connect("amqp://127.0.0.1:5672/%2f", "test-contract", ttr).unwrap()
+        })
+        .unwrap();
+    }
+    // test mongodb collections keep the delivery contract
+    #[cfg(feature = "mongodb")]
+    #[test]
+    fn test_mongodb_delivery_contract() {
+        use crate::mongodb::MongoQueue;
+        verify_delivery(|ttr| {
+            MongoQueue::connect("mongodb://127.0.0.1/queue_rs_test", "test-contract", ttr).unwrap()
+        })
+        .unwrap();
+    }
+    // test postgres tables keep the delivery contract
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn test_postgres_delivery_contract() {
+        use crate::postgres::PostgresQueue;
+        verify_delivery(|ttr| {
+            PostgresQueue::connect(
+                "postgres://postgres@127.0.0.1/postgres",
+                "test-contract",
+                ttr,
+            )
+            .unwrap()
+        })
+        .unwrap();
+    }
+    // test sqlite databases keep the delivery contract
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_delivery_contract() {
+        use crate::sqlite::SqliteQueue;
+        let path =
+            std::env::temp_dir().join(format!("queue-rs-sqlite-contract-{}", std::process::id()));
+        verify_delivery(|ttr| SqliteQueue::open(&path, "test-contract", ttr).unwrap()).unwrap();
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+    // test delayed jobs run once the fake clock reaches them
+    #[test]
+    fn test_advance() {
+        let mut queue = Queue::new(
+            "test-clock",
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+        );
+        queue.clear().unwrap();
+        queue.delay(600);
+        let mut test = TestQueue::new(queue).unwrap();
+        let id = test.queue().push(ReminderJob { user: 7 }).unwrap();
+        assert_eq!(test.advance(Duration::from_secs(300)).unwrap(), 0);
+        assert_eq!(test.run_waiting().unwrap(), 0);
+        assert_eq!(test.advance(Duration::from_secs(301)).unwrap(), 1);
+        assert_eq!(test.queue().status(&id).unwrap(), STATUS_WAITING);
+        assert_eq!(test.run_waiting().unwrap(), 1);
+        test.queue().clear().unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+use crate::error::{ErrorKind, QError};
+use crate::id::JobId;
+use crate::queue::Queue;
+use crate::{err, timestamp, QResult};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// The kinds of events a `Webhook` can be posted for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    JobBuried,
+    DepthBreached,
+    ChannelPaused,
+    WorkerDied,
+}
+
+/// The json body of a webhook, the `event` field names its kind
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// a job was buried, it waits to be kicked or removed
+    JobBuried {
+        channel: String,
+        id: JobId,
+        job_type: Option<String>,
+        buried_at: u64,
+    },
+    /// the waiting jobs of a channel reached the depth limit of the monitor
+    DepthBreached {
+        channel: String,
+        waiting: u64,
+        limit: u64,
+    },
+    /// the channel was paused, by hand or because too many jobs were buried
+    ChannelPaused { channel: String },
+    /// a worker stopped on an error
+    WorkerDied { channel: String, error: String },
+}
+
+impl WebhookEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            WebhookEvent::JobBuried { .. } => EventKind::JobBuried,
+            WebhookEvent::DepthBreached { .. } => EventKind::DepthBreached,
+            WebhookEvent::ChannelPaused { .. } => EventKind::ChannelPaused,
+            WebhookEvent::WorkerDied { .. } => EventKind::WorkerDied,
+        }
+    }
+}
+
+/// POSTs json events to a url. The `X-Queue-Rs-Signature` header is `sha256=` and the hex
+/// HMAC of `{X-Queue-Rs-Timestamp}.{body}` with the secret, receivers recompute it and
+/// reject old timestamps to stop replays
+pub struct Webhook {
+    url: String,
+    secret: Vec<u8>,
+    /// the kinds posted, every kind by default
+    events: HashSet<EventKind>,
+    timeout: Duration,
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url)
+            .field("events", &self.events)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl Webhook {
+    pub fn new(url: impl Into<String>, secret: impl Into<Vec<u8>>) -> Self {
+        Webhook {
+            url: url.into(),
+            secret: secret.into(),
+            events: HashSet::from([
+                EventKind::JobBuried,
+                EventKind::DepthBreached,
+                EventKind::ChannelPaused,
+                EventKind::WorkerDied,
+            ]),
+            timeout: Duration::from_secs(10),
+        }
+    }
+    /// post only events of these kinds
+    pub fn events(&mut self, events: &[EventKind]) -> &mut Self {
+        self.events = events.iter().copied().collect();
+        self
+    }
+    /// set how long a post may take
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+    /// post [event] if its kind is enabled, a non 2xx response is an error
+    pub fn send(&self, event: &WebhookEvent) -> QResult<()> {
+        if !self.events.contains(&event.kind()) {
+            return Ok(());
+        }
+        let body = serde_json::to_string(event)?;
+        let sent_at = timestamp()?.to_string();
+        let response = attohttpc::post(&self.url)
+            .timeout(self.timeout)
+            .header("Content-Type", "application/json")
+            .header("X-Queue-Rs-Timestamp", sent_at.as_str())
+            .header(
+                "X-Queue-Rs-Signature",
+                format!("sha256={}", sign(&self.secret, &sent_at, &body)),
+            )
+            .text(body)
+            .send()
+            .map_err(|e| QError::new(ErrorKind::Other, e.to_string()))?;
+        if !response.is_success() {
+            return err!(
+                "webhook [{}] answered {} to {:?}",
+                self.url,
+                response.status(),
+                event.kind()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// the hex HMAC-SHA256 of `{sent_at}.{body}`
+fn sign(secret: &[u8], sent_at: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts keys of any length");
+    mac.update(sent_at.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Watches a channel and posts a webhook when jobs are buried, the channel is paused or
+/// the waiting jobs reach the depth limit, the depth and pause events are posted once
+/// per breach and pause
+#[derive(Debug)]
+pub struct WebhookMonitor {
+    queue: Queue,
+    webhook: Webhook,
+    /// time between two checks
+    interval: Duration,
+    /// the waiting jobs which trigger `DepthBreached`, `None` never
+    depth_limit: Option<u64>,
+    stopping: AtomicBool,
+}
+
+/// What the monitor saw on its last check
+#[derive(Debug, Default)]
+struct Seen {
+    /// the buried time of the last posted job
+    buried_at: u64,
+    /// the posted jobs buried at `buried_at`, so jobs of the same second are posted once
+    ids: HashSet<JobId>,
+    breached: bool,
+    paused: bool,
+}
+
+impl WebhookMonitor {
+    pub fn new(queue: Queue, webhook: Webhook) -> Self {
+        WebhookMonitor {
+            queue,
+            webhook,
+            interval: Duration::from_secs(10),
+            depth_limit: None,
+            stopping: AtomicBool::new(false),
+        }
+    }
+    /// set the time between two checks
+    pub fn interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = interval;
+        self
+    }
+    /// set the number of waiting jobs which triggers `DepthBreached`
+    pub fn depth_limit(&mut self, limit: u64) -> &mut Self {
+        self.depth_limit = Some(limit);
+        self
+    }
+    /// stop `run` after the current check
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+    /// check until `stop` is called, jobs buried before the start are not posted,
+    /// errors are logged and the loop goes on
+    pub fn run(&self) -> QResult<()> {
+        let mut seen = Seen {
+            buried_at: timestamp()?,
+            ..Default::default()
+        };
+        info!("Webhook monitor started");
+        while !self.stopping.load(Ordering::SeqCst) {
+            if let Err(e) = self.check(&mut seen) {
+                error!("{}", e);
+            }
+            thread::sleep(self.interval);
+        }
+        Ok(())
+    }
+    /// post the jobs buried since the last check, a pause and a depth breach
+    fn check(&self, seen: &mut Seen) -> QResult<()> {
+        let overview = self.queue.overview()?;
+        for job in self.queue.buried_since(seen.buried_at, 0)? {
+            if job.buried_at == seen.buried_at && seen.ids.contains(&job.id) {
+                continue;
+            }
+            if job.buried_at > seen.buried_at {
+                seen.buried_at = job.buried_at;
+                seen.ids.clear();
+            }
+            seen.ids.insert(job.id.clone());
+            self.webhook.send(&WebhookEvent::JobBuried {
+                channel: overview.channel.clone(),
+                id: job.id,
+                job_type: job.job_type,
+                buried_at: job.buried_at,
+            })?;
+        }
+        let paused = self.queue.config()?.paused.unwrap_or(false);
+        if paused && !seen.paused {
+            self.webhook.send(&WebhookEvent::ChannelPaused {
+                channel: overview.channel.clone(),
+            })?;
+        }
+        seen.paused = paused;
+        if let Some(limit) = self.depth_limit {
+            let breached = overview.waiting >= limit;
+            if breached && !seen.breached {
+                self.webhook.send(&WebhookEvent::DepthBreached {
+                    channel: overview.channel,
+                    waiting: overview.waiting,
+                    limit,
+                })?;
+            }
+            seen.breached = breached;
+        }
+        Ok(())
+    }
+}
+
+// test webhook
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test the signature matches a known hmac
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign(b"secret", "1700000000", r#"{"event":"worker_died"}"#),
+            "096fa52bf98a8d0404b9d3a767feeb685cb3b8c869596e89844bf46e9e27fb2a"
+        );
+    }
+    // test event json carries its kind
+    #[test]
+    fn test_event_json() {
+        let event = WebhookEvent::DepthBreached {
+            channel: "mail".to_string(),
+            waiting: 120,
+            limit: 100,
+        };
+        assert_eq!(event.kind(), EventKind::DepthBreached);
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"depth_breached","channel":"mail","waiting":120,"limit":100}"#
+        );
+        let mut webhook = Webhook::new("http://127.0.0.1:9/", "secret");
+        webhook.events(&[EventKind::JobBuried]);
+        assert!(webhook.send(&event).is_ok());
+    }
+    // test the monitor posts the jobs buried by failures and the pause they lead to
+    #[test]
+    fn test_monitor_failures() {
+        use crate::job::JobTrait;
+        use crate::queue::ExecutionOutcome;
+        use serde::Deserialize;
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+        use std::sync::mpsc;
+
+        #[derive(Serialize, Deserialize)]
+        struct RefusedJob {}
+        #[typetag::serde]
+        impl JobTrait for RefusedJob {
+            fn execute(&self) -> QResult<()> {
+                err!("connection refused")
+            }
+        }
+        // answers each post with 200 and hands its body over
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (bodies, received) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                if bodies.send(body).is_err() {
+                    break;
+                }
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+            }
+        });
+        let mut queue = Queue::new(
+            "test-monitor-failures",
+            redis::Client::open("redis://127.0.0.1/").unwrap(),
+        );
+        queue.clear().unwrap();
+        queue.bury_limit(1, 60).retry_failed(true);
+        for _ in 0..2 {
+            queue.push(RefusedJob {}).unwrap();
+            let job = queue.reserve(0).unwrap();
+            let outcome = queue.handle_message(&job).unwrap();
+            assert!(matches!(outcome, ExecutionOutcome::Failed { .. }));
+            assert!(queue.settle(&job, &outcome).unwrap());
+        }
+        let monitor = WebhookMonitor::new(queue.clone(), Webhook::new(url, "secret"));
+        monitor.check(&mut Seen::default()).unwrap();
+        let events: Vec<String> = received
+            .try_iter()
+            .map(|body| body["event"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(events, ["job_buried", "job_buried", "channel_paused"]);
+        queue.clear().unwrap();
+    }
+}